@@ -3,10 +3,15 @@ extern crate freetype;
 extern crate image;
 
 use std::rc::Rc;
+use std::cell::Cell;
+use std::cell::RefCell;
+use std::mem;
 use std::cmp;
+use std::i32;
+use std::num::Int;
 use std::collections::hash_map::*;
+use std::collections::HashSet;
 use std::num::Float;
-use std::num::FromPrimitive;
 
 pub use self::Layout::*;
 pub use self::LayoutMinSize::*;
@@ -18,6 +23,7 @@ use glfw::Glfw;
 use glfw::Window;
 use glfw::Context;
 use std::sync::mpsc::Receiver;
+use std::old_io::IoResult;
 
 use vecmat::*;
 use renderer::*;
@@ -29,6 +35,7 @@ use mesh::*;
 use new_gl_program::*;
 use util::*;
 use color::*;
+use record::*;
 
 pub trait Widget {
   // Each widget must have a unique ID, generated from the global next_id() function
@@ -37,8 +44,54 @@ pub trait Widget {
 
   fn draw(&mut self, pos: Vec2<i32>, size: Vec2<i32>, window: &mut GUIWindow);
 
-  fn min_size(&self, window: &mut GUIWindow) -> Vec2<i32> {Vec2(0,0)}
+  fn min_size(&self, measure: &mut Measure) -> Vec2<i32> {Vec2(0,0)}
+  /// The smallest height the widget can have when it's `width` wide. Widgets whose contents
+  /// wrap should return the height they need at that width, and a min height that only
+  /// covers a single line.
+  fn height_for_width(&self, width: i32, measure: &mut Measure) -> i32 {self.min_size(measure).y}
+  /// The widget is never given more space than this; any extra space goes to other widgets
+  fn max_size(&self, measure: &mut Measure) -> Vec2<i32> {Vec2(UNBOUNDED, UNBOUNDED)}
+  /// The size the widget would like to have; it grows to this before flexible widgets are
+  /// given any extra space. This is clamped between the min and max sizes.
+  fn preferred_size(&self, measure: &mut Measure) -> Vec2<i32> {self.min_size(measure)}
+  /// Called for positional events that hit this widget or something in it, and keyboard
+  /// events when it or something in it is focused. Unless `GUIWindow::stop_propagation` is
+  /// called, the event then bubbles up to the containers it's in; keyboard events that
+  /// aren't stopped end up in `GUIWindow::get_unhandled_events`.
   fn handle_event(&mut self, event: Event, window: &mut GUIWindow) {}
+  /// Called for events sent to something inside this widget, before that widget gets them.
+  /// Containers can intercept events by calling `GUIWindow::stop_propagation`.
+  fn capture_event(&mut self, event: Event, window: &mut GUIWindow) {}
+
+  /// Returns true if the widget's size may have changed since the last time this was called.
+  /// The window remembers each widget's sizes and only asks for them again when this returns
+  /// true, so widgets that keep track of their changes should override it.
+  fn take_dirty(&mut self) -> bool {true}
+
+  /// Whether the widget can be focused with Tab and Shift+Tab
+  fn focusable(&self) -> bool {false}
+  /// Focusable widgets with a tab index come first in the tab order, from lowest to highest,
+  /// followed by the rest in layout order
+  fn tab_index(&self) -> Option<i32> {None}
+}
+
+// Lets a widget be shared between a `Node` tree and the code that uses it
+impl<T: Widget> Widget for Rc<RefCell<T>> {
+  fn id(&self) -> Id {self.borrow().id()}
+
+  fn draw(&mut self, pos: Vec2<i32>, size: Vec2<i32>, window: &mut GUIWindow) {
+    self.borrow_mut().draw(pos, size, window)
+  }
+
+  fn min_size(&self, measure: &mut Measure) -> Vec2<i32> {self.borrow().min_size(measure)}
+  fn max_size(&self, measure: &mut Measure) -> Vec2<i32> {self.borrow().max_size(measure)}
+  fn preferred_size(&self, measure: &mut Measure) -> Vec2<i32> {self.borrow().preferred_size(measure)}
+  fn height_for_width(&self, width: i32, measure: &mut Measure) -> i32 {self.borrow().height_for_width(width, measure)}
+  fn handle_event(&mut self, event: Event, window: &mut GUIWindow) {self.borrow_mut().handle_event(event, window)}
+  fn capture_event(&mut self, event: Event, window: &mut GUIWindow) {self.borrow_mut().capture_event(event, window)}
+  fn take_dirty(&mut self) -> bool {self.borrow_mut().take_dirty()}
+  fn focusable(&self) -> bool {self.borrow().focusable()}
+  fn tab_index(&self) -> Option<i32> {self.borrow().tab_index()}
 }
 
 impl<'a> PartialEq for Widget + 'a {
@@ -49,34 +102,215 @@ impl<'a> PartialEq for Widget + 'a {
 
 impl<'a> Eq for Widget + 'a {}
 
+/// What widgets need from a window to measure themselves. `GUIWindow` implements this, and
+/// other implementations can measure and arrange layouts without opening a window. Fonts are
+/// named by their keys, so measuring doesn't need the fonts to be loaded.
+pub trait Measure {
+  /// The number of pixels per logical unit
+  fn content_scale(&self) -> f32 {1.0}
+  /// The size of a line of text in logical units
+  fn string_size(&mut self, font: FontKey, text: &str) -> Vec2<i32>;
+  fn string_width(&mut self, font: FontKey, text: &str) -> i32 {self.string_size(font, text).x}
+  /// The vertical distance between lines of text
+  fn vert_advance(&mut self, font: FontKey) -> i32;
+}
+
+
+
+
+
 
 
+/// Used as a maximum size when there's no limit on how large something can be
+pub const UNBOUNDED: i32 = i32::MAX;
+
+/// The sizes a layout can be given
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct SizeHints {
+  pub min: Vec2<i32>,
+  pub preferred: Vec2<i32>,
+  pub max: Vec2<i32>,
+}
+
+impl SizeHints {
+  /// Makes sure that min <= preferred <= max
+  pub fn new(min: Vec2<i32>, preferred: Vec2<i32>, max: Vec2<i32>) -> SizeHints {
+    let max = max.component_max(min);
+    let preferred = Vec2(cmp::min(preferred.x, max.x), cmp::min(preferred.y, max.y)).component_max(min);
+    SizeHints{min: min, preferred: preferred, max: max}
+  }
+
+  /// Something that can be any size larger than `min`
+  pub fn at_least(min: Vec2<i32>) -> SizeHints {
+    SizeHints::new(min, min, Vec2(UNBOUNDED, UNBOUNDED))
+  }
+
+  /// The size to give something that's been allocated `size` along an axis where it isn't
+  /// flexed, such as the width of a child of a VPanel
+  fn clamp(&self, size: Vec2<i32>) -> Vec2<i32> {
+    Vec2(cmp::min(size.x, self.max.x), cmp::min(size.y, self.max.y)).component_max(self.min)
+  }
+
+  // Adds some fixed amount of space, such as padding
+  fn grow(&self, amount: Vec2<i32>) -> SizeHints {
+    SizeHints{min: self.min + amount, preferred: self.preferred + amount,
+      max: Vec2(self.max.x.saturating_add(amount.x), self.max.y.saturating_add(amount.y))}
+  }
+}
+
+// One of the things sharing space along an axis, such as a child of an HPanel
+struct AxisItem {
+  min: i32,
+  preferred: i32,
+  max: i32,
+  flex: f64,
+}
 
+// Splits `total` between the items. Each item gets at least its min size and then grows
+// towards its preferred size. What's left is shared according to the flexes, without making
+// anything larger than its max size; space that one item can't take is passed on to the
+// others, and pixels left over from rounding go to the first items that can take them.
+fn distribute(items: &[AxisItem], total: i32) -> Vec<i32> {
+  let mut sizes: Vec<i32> = items.iter().map(|item| item.min).collect();
+  let mut remaining = total - sizes.iter().fold(0, |a, &b| a + b);
+  if remaining <= 0 {
+    return sizes;
+  }
 
+  let wanted = items.iter().fold(0, |a, item| a + item.preferred - item.min);
+  if wanted > remaining {
+    let mut given = 0;
+    for (i, item) in items.iter().enumerate() {
+      let amount = ((item.preferred - item.min) as f64 * remaining as f64 / wanted as f64).floor() as i32;
+      sizes[i] += amount;
+      given += amount;
+    }
+    for (i, item) in items.iter().enumerate() {
+      if given < remaining && sizes[i] < item.preferred {
+        sizes[i] += 1;
+        given += 1;
+      }
+    }
+    return sizes;
+  }
+  for (i, item) in items.iter().enumerate() {
+    sizes[i] = item.preferred;
+  }
+  remaining -= wanted;
 
+  while remaining > 0 {
+    let open: Vec<usize> = range(0, items.len()).filter(|&i| items[i].flex > 0.0 && sizes[i] < items[i].max).collect();
+    if open.is_empty() {
+      break;
+    }
+    let open_flex = open.iter().fold(0.0, |a, &i| a + items[i].flex);
+    let mut given = 0;
+    for &i in open.iter() {
+      let share = (remaining as f64 * items[i].flex / open_flex).floor() as i32;
+      let amount = cmp::min(share, items[i].max - sizes[i]);
+      sizes[i] += amount;
+      given += amount;
+    }
+    if given == 0 {
+      // Everyone's share rounded down to nothing, so hand out single pixels
+      for &i in open.iter() {
+        if given < remaining {
+          sizes[i] += 1;
+          given += 1;
+        }
+      }
+    }
+    remaining -= given;
+  }
+  sizes
+}
 
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum Alignment {Leading, Center, Trailing}
 
-// TODO: implement grids
+/// The direction text and horizontal layouts run in. In `RightToLeft`, `HPanel`s and other
+/// panels are mirrored, so the first child is on the right, and `Leading` means the right edge.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Direction {LeftToRight, RightToLeft}
+
+impl Direction {
+  // The alignment a horizontal alignment has in left to right terms
+  fn resolve(self, align: Alignment) -> Alignment {
+    match (self, align) {
+      (Direction::RightToLeft, Leading) => Trailing,
+      (Direction::RightToLeft, Trailing) => Leading,
+      (_, align) => align,
+    }
+  }
+
+  // The x position of something `width` wide placed `offset` from the start of a line at
+  // `pos` that's `avail` wide
+  fn place(self, pos: i32, avail: i32, offset: i32, width: i32) -> i32 {
+    match self {
+      Direction::LeftToRight => pos + offset,
+      Direction::RightToLeft => pos + avail - offset - width,
+    }
+  }
+}
+
 pub enum Layout<'a> {
-  VPanel(Alignment, Vec<(Layout<'a>, f64)>),
-  HPanel(Alignment, Vec<(Layout<'a>, f64)>),
-  // TODO: allow alignment of children
-  OverlapPanel(Vec<Layout<'a>>),
-  //OverlapPanel(Vec<Layout<'a>),  // TODO
+  VPanel(Alignment, Spacing, Vec<(Layout<'a>, f64)>),
+  HPanel(Alignment, Spacing, Vec<(Layout<'a>, f64)>),
+  /// The row flexes, the column flexes, and the cells. The number of rows and columns is
+  /// the length of the corresponding flex vector.
+  Grid(Vec<f64>, Vec<f64>, Vec<GridCell<Layout<'a>>>),
+  /// Children are drawn in order, on top of each other
+  OverlapPanel(Vec<(Layout<'a>, Placement)>),
+  /// Places children left to right at their preferred sizes, starting a new line when
+  /// there's no more room. The alignment is that of each line, and the i32 is the space
+  /// between lines; `Spacing::between` is the space between children on the same line.
+  FlowPanel(Alignment, Spacing, i32, Vec<Layout<'a>>),
+  /// Shows part of a child that may be larger than the panel. The state must be kept
+  /// between frames so the panel stays scrolled.
+  ScrollPanel(&'a mut ScrollState, Box<Layout<'a>>),
+  /// Remembers where its child was placed, and doesn't lay it out again until it's moved or
+  /// resized or something inside it changes
+  Cached(&'a mut LayoutCache, Box<Layout<'a>>),
+  /// A retained tree; panels in it that haven't changed since the last frame aren't measured
+  /// or arranged again. Made by `Node::as_layout`.
+  Tree(&'a mut Node),
+  /// Places children side by side along the state's axis, with dividers between them that
+  /// the user can drag to resize them
+  Splitter(&'a mut SplitterState, Vec<Layout<'a>>),
+  /// Shows only the child at the given index. The stack is large enough for any of its
+  /// children, so it doesn't change size when a different child is shown.
+  Stack(usize, Vec<Layout<'a>>),
+  /// A stack with a strip of tabs above it, one for each child, that can be clicked to choose
+  /// the child that's shown
+  Tabs(&'a mut TabState, Vec<(&'a str, Layout<'a>)>),
+  /// Attaches children to the edges of the panel, in order; each one takes space from what's
+  /// left by the ones before it. Each `Fill` child gets all of whatever's left at the end, so
+  /// several of them overlap like the children of an `OverlapPanel`.
+  Dock(Vec<(Layout<'a>, DockEdge)>),
+  /// Lays out its child in the given direction, regardless of the window's direction
+  Directed(Direction, Box<Layout<'a>>),
   LWidget(&'a mut (Widget + 'a)),
 }
 
 impl<'a> Layout<'a> {
   // Also adjusts the flexes to sum to 1
-  fn into_layout_min_size(self, window: &mut GUIWindow) -> LayoutMinSize<'a> {
+  fn into_layout_min_size(self, cx: &mut LayoutContext) -> LayoutMinSize<'a> {
     match self {
       LWidget(widget) => {
-        let min_size = widget.min_size(window);
-        LWidget_(widget, min_size)
+        let id = widget.id();
+        cx.state.measured_widgets.insert(id);
+        // Widgets in a tree may have said they changed when the tree was checked for changes
+        let hints = if widget.take_dirty() || cx.state.dirty_widgets.remove(&id) || !cx.state.widget_hints.contains_key(&id) {
+          let hints = SizeHints::new(widget.min_size(cx.measure), widget.preferred_size(cx.measure), widget.max_size(cx.measure));
+          cx.state.widget_hints.insert(id, hints);
+          cx.state.layout_changes += 1;
+          hints
+        } else {
+          *cx.state.widget_hints.get(&id).unwrap()
+        };
+        LWidget_(widget, hints)
       }
-      VPanel(alignment, children) => {
-        let mut min_size = Vec2::zero();
+      VPanel(alignment, spacing, children) => {
         let mut new_children = Vec::new();
         let mut total_flex = 0.0;
         for &(_, flex) in children.iter() {
@@ -85,17 +319,29 @@ impl<'a> Layout<'a> {
         if total_flex == 0.0 {
           total_flex = 1.0;
         }
+        // The children can always be spread out across the panel, so it doesn't have a max width
+        let mut min_size = Vec2::zero();
+        let mut preferred = Vec2::zero();
+        let mut max = Vec2(UNBOUNDED, 0);
         for (layout, flex) in children.into_iter() {
-          let layout = layout.into_layout_min_size(window);
-          let layout_min_size = layout.min_size();
-          min_size.x = cmp::max(min_size.x, layout_min_size.x);
-          min_size.y += layout_min_size.y;
+          let layout = layout.into_layout_min_size(cx);
+          let hints = layout.hints();
+          min_size.x = cmp::max(min_size.x, hints.min.x);
+          min_size.y += hints.min.y;
+          preferred.x = cmp::max(preferred.x, hints.preferred.x);
+          preferred.y += hints.preferred.y;
+          // A child that doesn't flex never grows past its preferred size
+          max.y = max.y.saturating_add(if flex > 0.0 {hints.max.y} else {hints.preferred.y});
           new_children.push((layout, flex/total_flex));
         }
-        VPanel_(alignment, new_children, min_size)
+        let between = spacing.total_between(new_children.len());
+        min_size.y += between;
+        preferred.y += between;
+        max.y = max.y.saturating_add(between);
+        let hints = SizeHints::new(min_size, preferred, max).grow(spacing.padding.size());
+        VPanel_(alignment, spacing, new_children, hints)
       }
-      HPanel(alignment, children) => {
-        let mut min_size = Vec2::zero();
+      HPanel(alignment, spacing, children) => {
         let mut new_children = Vec::new();
         let mut total_flex = 0.0;
         for &(_, flex) in children.iter() {
@@ -104,109 +350,1743 @@ impl<'a> Layout<'a> {
         if total_flex == 0.0 {
           total_flex = 1.0;
         }
+        // The children can always be spread out across the panel, so it doesn't have a max height
+        let mut min_size = Vec2::zero();
+        let mut preferred = Vec2::zero();
+        let mut max = Vec2(0, UNBOUNDED);
         for (layout, flex) in children.into_iter() {
-          let layout = layout.into_layout_min_size(window);
-          let layout_min_size = layout.min_size();
-          min_size.y = cmp::max(min_size.y, layout_min_size.y);
-          min_size.x += layout_min_size.x;
+          let layout = layout.into_layout_min_size(cx);
+          let hints = layout.hints();
+          min_size.y = cmp::max(min_size.y, hints.min.y);
+          min_size.x += hints.min.x;
+          preferred.y = cmp::max(preferred.y, hints.preferred.y);
+          preferred.x += hints.preferred.x;
+          // A child that doesn't flex never grows past its preferred size
+          max.x = max.x.saturating_add(if flex > 0.0 {hints.max.x} else {hints.preferred.x});
           new_children.push((layout, flex/total_flex));
         }
-        HPanel_(alignment, new_children, min_size)
+        let between = spacing.total_between(new_children.len());
+        min_size.x += between;
+        preferred.x += between;
+        max.x = max.x.saturating_add(between);
+        let hints = SizeHints::new(min_size, preferred, max).grow(spacing.padding.size());
+        HPanel_(alignment, spacing, new_children, hints)
+      }
+      FlowPanel(alignment, spacing, line_spacing, children) => {
+        let mut min_size = Vec2::zero();
+        let mut preferred = Vec2::zero();
+        let mut new_children = Vec::new();
+        for layout in children.into_iter() {
+          let layout = layout.into_layout_min_size(cx);
+          let hints = layout.hints();
+          // It can always be squeezed down to one child per line; the real min height
+          // depends on the width, and comes from height_for_width
+          min_size = min_size.component_max(hints.min);
+          preferred.x += hints.preferred.x;
+          preferred.y = cmp::max(preferred.y, hints.preferred.y);
+          new_children.push(layout);
+        }
+        preferred.x += spacing.total_between(new_children.len());
+        let hints = SizeHints::new(min_size, preferred, Vec2(UNBOUNDED, UNBOUNDED)).grow(spacing.padding.size());
+        FlowPanel_(alignment, spacing, line_spacing, new_children, hints)
       }
       OverlapPanel(children) => {
         let mut min_size = Vec2::zero();
+        let mut preferred = Vec2::zero();
+        let mut new_children = Vec::new();
+        for (layout, placement) in children.into_iter() {
+          let layout = layout.into_layout_min_size(cx);
+          let offset = Vec2(placement.offset.x.abs(), placement.offset.y.abs());
+          min_size = min_size.component_max(layout.min_size() + offset);
+          preferred = preferred.component_max(layout.hints().preferred + offset);
+          new_children.push((layout, placement));
+        }
+        let hints = SizeHints::new(min_size, preferred, Vec2(UNBOUNDED, UNBOUNDED));
+        OverlapPanel_(new_children, hints)
+      }
+      Grid(row_flexes, col_flexes, cells) => {
+        let mut rows = GridTracks::new(row_flexes);
+        let mut cols = GridTracks::new(col_flexes);
+        let mut new_cells = Vec::new();
+        for cell in cells.into_iter() {
+          assert!(cell.row_span > 0 && cell.col_span > 0, "Grid cells must span at least one row and column");
+          assert!(cell.row + cell.row_span <= rows.len() && cell.col + cell.col_span <= cols.len(),
+            "Grid cell is outside the grid");
+          new_cells.push(cell.map(|layout| layout.into_layout_min_size(cx)));
+        }
+        let hints: Vec<SizeHints> = new_cells.iter().map(|cell| cell.layout.hints()).collect();
+        let col_spans: Vec<(usize, usize)> = new_cells.iter().map(|cell| (cell.col, cell.col_span)).collect();
+        cols.fit_cells(col_spans.clone(), hints.iter().map(|hints| hints.min.x).collect());
+        cols.fit_preferred(col_spans.clone(), hints.iter().map(|hints| hints.preferred.x).collect());
+        cols.limit(col_spans, hints.iter().map(|hints| hints.max.x).collect());
+        let row_spans: Vec<(usize, usize)> = new_cells.iter().map(|cell| (cell.row, cell.row_span)).collect();
+        rows.fit_cells(row_spans.clone(), hints.iter().map(|hints| hints.min.y).collect());
+        rows.fit_preferred(row_spans.clone(), hints.iter().map(|hints| hints.preferred.y).collect());
+        rows.limit(row_spans, hints.iter().map(|hints| hints.max.y).collect());
+        let hints = SizeHints::new(Vec2(cols.min_size(), rows.min_size()), Vec2(cols.preferred_size(), rows.preferred_size()),
+          Vec2(cols.max_size(), rows.max_size()));
+        Grid_(rows, cols, new_cells, hints)
+      }
+      ScrollPanel(state, child) => {
+        if state.laid_out_offset.get() != Some(state.offset()) {
+          cx.state.layout_changes += 1;
+        }
+        let child = child.into_layout_min_size(cx);
+        let child_hints = child.hints();
+        let bars = state.bars_size();
+        // The panel can be as small as its scroll bars along the axes it scrolls on
+        let min_size = Vec2(if state.horizontal {0} else {child_hints.min.x},
+          if state.vertical {0} else {child_hints.min.y}) + bars;
+        let hints = SizeHints::new(min_size, child_hints.preferred + bars, Vec2(UNBOUNDED, UNBOUNDED));
+        ScrollPanel_(state, Box::new(child), hints)
+      }
+      Splitter(state, children) => {
+        if state.changed {
+          state.changed = false;
+          cx.state.layout_changes += 1;
+        }
+        let axis = state.axis;
+        let mut main = state.dividers_size(children.len());
+        let mut cross = 0;
+        let mut preferred_main = main;
+        let mut preferred_cross = 0;
+        let mut new_children = Vec::new();
+        for layout in children.into_iter() {
+          let layout = layout.into_layout_min_size(cx);
+          let hints = layout.hints();
+          main += axis.main(hints.min);
+          cross = cmp::max(cross, axis.cross(hints.min));
+          preferred_main += axis.main(hints.preferred);
+          preferred_cross = cmp::max(preferred_cross, axis.cross(hints.preferred));
+          new_children.push(layout);
+        }
+        let hints = SizeHints::new(axis.vec(main, cross), axis.vec(preferred_main, preferred_cross), Vec2(UNBOUNDED, UNBOUNDED));
+        Splitter_(state, new_children, hints)
+      }
+      Stack(index, children) => {
+        let mut min_size = Vec2::zero();
+        let mut preferred = Vec2::zero();
         let mut new_children = Vec::new();
         for layout in children.into_iter() {
-          let layout = layout.into_layout_min_size(window);
+          let layout = layout.into_layout_min_size(cx);
           min_size = min_size.component_max(layout.min_size());
+          preferred = preferred.component_max(layout.hints().preferred);
           new_children.push(layout);
         }
-        OverlapPanel_(new_children, min_size)
+        Stack_(index, new_children, SizeHints::new(min_size, preferred, Vec2(UNBOUNDED, UNBOUNDED)))
+      }
+      Tabs(state, children) => {
+        let (titles, layouts): (Vec<&str>, Vec<Layout>) = children.into_iter().unzip();
+        // Tabs may have been removed since the selection was made
+        let last = if titles.is_empty() {0} else {titles.len() - 1};
+        state.selected = cmp::min(state.selected, last);
+        let selected = state.selected;
+        if state.direction != cx.state.direction {
+          // The tabs are mirrored, so they have to be drawn again
+          state.direction = cx.state.direction;
+          state.dirty = true;
+        }
+        if state.set_titles(titles) || state.shown != Some(selected) {
+          state.shown = Some(selected);
+          cx.state.layout_changes += 1;
+        }
+        VPanel(Leading, Spacing::none(), vec![(LWidget(state), 0.0), (Stack(selected, layouts), 1.0)])
+          .into_layout_min_size(cx)
+      }
+      Dock(children) => {
+        let mut new_children = Vec::new();
+        for (layout, edge) in children.into_iter() {
+          new_children.push((layout.into_layout_min_size(cx), edge));
+        }
+        // Work backwards from the space left at the end, adding each edge's child around it
+        let mut min_size = Vec2::zero();
+        let mut preferred = Vec2::zero();
+        for &(ref child, edge) in new_children.iter() {
+          if edge == DockEdge::Fill {
+            min_size = min_size.component_max(child.min_size());
+            preferred = preferred.component_max(child.hints().preferred);
+          }
+        }
+        for &(ref child, edge) in new_children.iter().rev() {
+          let hints = child.hints();
+          match edge {
+            DockEdge::Top | DockEdge::Bottom => {
+              min_size = Vec2(cmp::max(min_size.x, hints.min.x), min_size.y + hints.min.y);
+              preferred = Vec2(cmp::max(preferred.x, hints.preferred.x), preferred.y + hints.preferred.y);
+            }
+            DockEdge::Left | DockEdge::Right => {
+              min_size = Vec2(min_size.x + hints.min.x, cmp::max(min_size.y, hints.min.y));
+              preferred = Vec2(preferred.x + hints.preferred.x, cmp::max(preferred.y, hints.preferred.y));
+            }
+            DockEdge::Fill => (),
+          }
+        }
+        Dock_(new_children, SizeHints::new(min_size, preferred, Vec2(UNBOUNDED, UNBOUNDED)))
+      }
+      Directed(direction, child) => {
+        // Widgets like tab strips take their direction when they're measured
+        let outer = cx.state.direction;
+        cx.state.direction = direction;
+        let child = child.into_layout_min_size(cx);
+        cx.state.direction = outer;
+        Directed_(direction, Box::new(child))
+      }
+      Cached(cache, child) => {
+        let changes = cx.state.layout_changes;
+        let child = child.into_layout_min_size(cx);
+        let dirty = cache.invalid || cx.state.layout_changes != changes;
+        if dirty {
+          cache.invalid = false;
+          cache.height.set(None);
+          // So that the caches this is nested in know something changed
+          cx.state.layout_changes += 1;
+        }
+        Cached_(cache, Box::new(child), dirty)
+      }
+      Tree(node) => {
+        let changed = node.poll_changes(cx.state);
+        let hints = node.cache.hints;
+        let widget = node.is_widget();
+        match hints {
+          // Its children are only measured if it has to be arranged again
+          Some(hints) if !changed && !widget => Tree_(RefCell::new(TreeChild::Unmeasured(node)), hints),
+          _ => node.measure(changed, cx),
+        }
+      }
+    }
+  }
+}
+
+impl<'a> Layout<'a> {
+  /// Measures the layout and arranges it at the origin, filling `size` or the layout's min
+  /// size if that's larger, without drawing it. This doesn't need a window, so layouts can be
+  /// tested with a stand-in `Measure`. Returns the size the layout was given; the widgets'
+  /// positions and sizes are in `state`.
+  pub fn arrange(self, size: Vec2<i32>, state: &mut LayoutState, measure: &mut Measure) -> Vec2<i32> {
+    state.begin_pass();
+    let mut cx = LayoutContext{measure: measure, state: state};
+    let layout = self.into_layout_min_size(&mut cx);
+    let mut min_size = layout.min_size();
+    min_size.y = layout.height_for_width(cmp::max(size.x, min_size.x), &mut cx);
+    let real_size = size.component_max(min_size);
+    layout.calc_pos_size(Vec2::zero(), real_size, Rect(Vec2::zero(), real_size), &mut cx);
+    let mut widgets = Vec::new();
+    layout.collect_widgets(&mut widgets, &mut HashMap::new(), None);
+    cx.state.forget_missing(widgets.as_slice());
+    real_size
+  }
+}
+
+/// What's remembered about a layout between frames: the sizes its widgets reported and where
+/// they were placed. Only the parts of a layout that change are measured and arranged again.
+pub struct LayoutState {
+  /// The direction horizontal layouts run in, unless a `Directed` layout overrides it
+  pub direction: Direction,
+  widget_poses: HashMap<Id, Vec2<i32>>,
+  widget_sizes: HashMap<Id, Vec2<i32>>,
+  // The part of the window each widget is visible in; this is smaller than the widget if
+  // it's been scrolled partly out of view
+  widget_clips: HashMap<Id, Rect<i32>>,
+  // The sizes each widget reported the last time it was dirty
+  widget_hints: HashMap<Id, SizeHints>,
+  // Incremented whenever something is measured again, so Cached layouts can tell whether
+  // anything inside them changed
+  layout_changes: u64,
+  // Changed by invalidate; the sizes trees remember are only used if they were measured
+  // with the same generation
+  generation: Id,
+  // Different for every layout pass, so each node of a tree is only checked for changes once
+  pass: Id,
+  // Widgets in trees that said they changed when the trees were checked this pass
+  dirty_widgets: HashSet<Id>,
+  // The widgets measured this frame, including ones that are hidden; the others are
+  // removed from widget_hints unless they're still in the layout
+  measured_widgets: HashSet<Id>,
+}
+
+impl LayoutState {
+  pub fn new() -> LayoutState {
+    LayoutState{direction: Direction::LeftToRight,
+      widget_poses: HashMap::new(), widget_sizes: HashMap::new(), widget_clips: HashMap::new(),
+      widget_hints: HashMap::new(), layout_changes: 0, generation: next_id(), pass: next_id(),
+      dirty_widgets: HashSet::new(), measured_widgets: HashSet::new()}
+  }
+
+  /// Where a widget was placed, relative to the top left of the layout
+  pub fn widget_pos(&self, id: Id) -> Option<Vec2<i32>> {
+    self.widget_poses.get(&id).map(|&pos| pos)
+  }
+
+  pub fn widget_size(&self, id: Id) -> Option<Vec2<i32>> {
+    self.widget_sizes.get(&id).map(|&size| size)
+  }
+
+  /// The part of the layout a widget is visible in
+  pub fn widget_clip(&self, id: Id) -> Option<Rect<i32>> {
+    self.widget_clips.get(&id).map(|&clip| clip)
+  }
+
+  /// Forgets everything that's been measured, such as when the content scale changes
+  pub fn invalidate(&mut self) {
+    self.widget_hints.clear();
+    self.layout_changes += 1;
+    self.generation = next_id();
+  }
+
+  // Called before a layout is measured
+  fn begin_pass(&mut self) {
+    self.measured_widgets.clear();
+    self.dirty_widgets.clear();
+    self.pass = next_id();
+  }
+
+  // Forgets about widgets that are no longer in the layout
+  fn forget_missing<'w>(&mut self, widgets: &[&'w mut (Widget + 'w)]) {
+    let live_ids: HashSet<Id> = widgets.iter().map(|widget| widget.id()).collect();
+    retain_ids(&mut self.widget_poses, &live_ids);
+    retain_ids(&mut self.widget_sizes, &live_ids);
+    retain_ids(&mut self.widget_clips, &live_ids);
+    // Widgets in parts of trees that weren't measured again are still in the layout
+    let mut measured_widgets = mem::replace(&mut self.measured_widgets, HashSet::new());
+    measured_widgets.extend(live_ids.into_iter());
+    retain_ids(&mut self.widget_hints, &measured_widgets);
+  }
+}
+
+// Passed through a layout while it's measured and arranged
+struct LayoutContext<'a> {
+  measure: &'a mut (Measure + 'a),
+  state: &'a mut LayoutState,
+}
+
+/// Empty space around the edges of a panel
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Padding {
+  pub top: i32,
+  pub right: i32,
+  pub bottom: i32,
+  pub left: i32,
+}
+
+impl Padding {
+  pub fn zero() -> Padding {
+    Padding::uniform(0)
+  }
+
+  pub fn uniform(amount: i32) -> Padding {
+    Padding{top: amount, right: amount, bottom: amount, left: amount}
+  }
+
+  /// The total horizontal and vertical padding
+  pub fn size(&self) -> Vec2<i32> {
+    Vec2(self.left + self.right, self.top + self.bottom)
+  }
+
+  // Shrinks a rectangle by the padding
+  fn inset(&self, pos: Vec2<i32>, size: Vec2<i32>) -> (Vec2<i32>, Vec2<i32>) {
+    (pos + Vec2(self.left, self.top), size - self.size())
+  }
+}
+
+/// The whitespace in a `VPanel` or `HPanel`
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Spacing {
+  /// Space between the edges of the panel and its children
+  pub padding: Padding,
+  /// Space between adjacent children
+  pub between: i32,
+}
+
+impl Spacing {
+  /// No space at all; children are packed against each other and the panel's edges
+  pub fn none() -> Spacing {
+    Spacing{padding: Padding::zero(), between: 0}
+  }
+
+  pub fn new(padding: i32, between: i32) -> Spacing {
+    Spacing{padding: Padding::uniform(padding), between: between}
+  }
+
+  fn total_between(&self, num_children: usize) -> i32 {
+    if num_children == 0 {0} else {self.between * (num_children - 1) as i32}
+  }
+}
+
+/// Keeps track of how far a `ScrollPanel` has been scrolled. This is drawn as the panel's
+/// scroll bars, which can be dragged.
+pub struct ScrollState {
+  id: Id,
+  pub horizontal: bool,
+  pub vertical: bool,
+  pub bar_color: Color<f32>,
+  offset: Cell<Vec2<i32>>,
+  // These are updated every time the panel is laid out
+  view_size: Cell<Vec2<i32>>,
+  content_size: Cell<Vec2<i32>>,
+  // The offset the last layout used; if this is different, the layout needs to be redone
+  laid_out_offset: Cell<Option<Vec2<i32>>>,
+  // The axis of the scroll bar being dragged, and where it was grabbed relative to the
+  // start of its thumb
+  dragging: Option<(Axis, i32)>,
+}
+
+/// A direction things can be laid out or scrolled in
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Axis {Horizontal, Vertical}
+
+impl Axis {
+  /// The component of a vector along this axis
+  pub fn main(&self, vec: Vec2<i32>) -> i32 {
+    match *self {
+      Axis::Horizontal => vec.x,
+      Axis::Vertical => vec.y,
+    }
+  }
+
+  /// The component of a vector along the other axis
+  pub fn cross(&self, vec: Vec2<i32>) -> i32 {
+    match *self {
+      Axis::Horizontal => vec.y,
+      Axis::Vertical => vec.x,
+    }
+  }
+
+  /// Builds a vector from its components along this axis and the other one
+  pub fn vec(&self, main: i32, cross: i32) -> Vec2<i32> {
+    match *self {
+      Axis::Horizontal => Vec2(main, cross),
+      Axis::Vertical => Vec2(cross, main),
+    }
+  }
+}
+
+/// The thickness of a scroll bar
+pub const SCROLL_BAR_SIZE: i32 = 10;
+/// How far a scroll panel moves for each step of the scroll wheel
+pub const SCROLL_STEP: f64 = 40.0;
+// Scroll bar thumbs never get smaller than this
+const MIN_THUMB_SIZE: i32 = 20;
+
+impl ScrollState {
+  pub fn new(horizontal: bool, vertical: bool, bar_color: Color<f32>) -> ScrollState {
+    ScrollState{id: next_id(), horizontal: horizontal, vertical: vertical, bar_color: bar_color,
+      offset: Cell::new(Vec2::zero()), view_size: Cell::new(Vec2::zero()), content_size: Cell::new(Vec2::zero()),
+      laid_out_offset: Cell::new(None), dragging: None}
+  }
+
+  /// How far the content has been scrolled
+  pub fn offset(&self) -> Vec2<i32> {self.offset.get()}
+
+  /// Scrolls to the given offset; this is limited to the size of the content
+  pub fn scroll_to(&mut self, offset: Vec2<i32>) {
+    self.offset.set(offset);
+    self.clamp_offset();
+  }
+
+  pub fn scroll_by(&mut self, amount: Vec2<i32>) {
+    let offset = self.offset() + amount;
+    self.scroll_to(offset);
+  }
+
+  // The space taken up by the scroll bars
+  fn bars_size(&self) -> Vec2<i32> {
+    Vec2(if self.vertical {SCROLL_BAR_SIZE} else {0}, if self.horizontal {SCROLL_BAR_SIZE} else {0})
+  }
+
+  // The size of the area the content is shown in, when the panel is `size` large
+  fn viewport(&self, size: Vec2<i32>) -> Vec2<i32> {
+    (size - self.bars_size()).component_max(Vec2::zero())
+  }
+
+  fn set_sizes(&self, view_size: Vec2<i32>, content_size: Vec2<i32>) {
+    self.view_size.set(view_size);
+    self.content_size.set(content_size);
+    self.clamp_offset();
+  }
+
+  fn clamp_offset(&self) {
+    let max_offset = (self.content_size.get() - self.view_size.get()).component_max(Vec2::zero());
+    let offset = self.offset.get();
+    let offset = Vec2(cmp::min(offset.x, max_offset.x), cmp::min(offset.y, max_offset.y)).component_max(Vec2::zero());
+    self.offset.set(offset);
+  }
+
+  // The position and length of a scroll bar's thumb along its axis, relative to the start
+  // of the bar
+  fn thumb(&self, axis: Axis) -> (i32, i32) {
+    let (view, content, offset) = match axis {
+      Axis::Horizontal => (self.view_size.get().x, self.content_size.get().x, self.offset().x),
+      Axis::Vertical => (self.view_size.get().y, self.content_size.get().y, self.offset().y),
+    };
+    if content <= view {
+      return (0, view);
+    }
+    let length = cmp::min(cmp::max(view * view / content, MIN_THUMB_SIZE), view);
+    (offset * (view - length) / (content - view), length)
+  }
+
+  // The position and size of a scroll bar's thumb, relative to the panel
+  fn thumb_rect(&self, axis: Axis) -> (Vec2<i32>, Vec2<i32>) {
+    let view_size = self.view_size.get();
+    let (start, length) = self.thumb(axis);
+    match axis {
+      Axis::Horizontal => (Vec2(start, view_size.y), Vec2(length, SCROLL_BAR_SIZE)),
+      Axis::Vertical => (Vec2(view_size.x, start), Vec2(SCROLL_BAR_SIZE, length)),
+    }
+  }
+
+  fn axes(&self) -> Vec<Axis> {
+    let mut axes = Vec::new();
+    if self.horizontal {axes.push(Axis::Horizontal);}
+    if self.vertical {axes.push(Axis::Vertical);}
+    axes
+  }
+
+  // Moves a dragged thumb so it starts at `thumb_start` along its bar
+  fn drag_thumb(&mut self, axis: Axis, thumb_start: i32) {
+    let (view, content) = match axis {
+      Axis::Horizontal => (self.view_size.get().x, self.content_size.get().x),
+      Axis::Vertical => (self.view_size.get().y, self.content_size.get().y),
+    };
+    let (_, length) = self.thumb(axis);
+    if view == length {
+      return;
+    }
+    let offset = thumb_start * (content - view) / (view - length);
+    let mut new_offset = self.offset();
+    match axis {
+      Axis::Horizontal => new_offset.x = offset,
+      Axis::Vertical => new_offset.y = offset,
+    }
+    self.scroll_to(new_offset);
+  }
+}
+
+impl Widget for ScrollState {
+  fn id(&self) -> Id {self.id}
+
+  fn draw(&mut self, pos: Vec2<i32>, size: Vec2<i32>, window: &mut GUIWindow) {
+    for &axis in self.axes().iter() {
+      let (thumb_pos, thumb_size) = self.thumb_rect(axis);
+      window.fill_rect(pos + thumb_pos, thumb_size, self.bar_color);
+    }
+  }
+
+  fn handle_event(&mut self, event: Event, window: &mut GUIWindow) {
+    match event {
+      Event::MouseButton(glfw::MouseButtonLeft, Action::Press, _, pos) => {
+        for &axis in self.axes().iter() {
+          let (thumb_pos, thumb_size) = self.thumb_rect(axis);
+          if AABB2::from_pos_size(thumb_pos, thumb_size).contains_vec(pos) {
+            let grab = match axis {
+              Axis::Horizontal => pos.x - thumb_pos.x,
+              Axis::Vertical => pos.y - thumb_pos.y,
+            };
+            self.dragging = Some((axis, grab));
+            window.capture_pointer(self.id);
+          }
+        }
+      }
+      Event::MouseButton(glfw::MouseButtonLeft, Action::Release, _, _) => if self.dragging.is_some() {
+        self.dragging = None;
+        window.release_pointer();
+      },
+      Event::MouseMove(pos, _) => match self.dragging {
+        Some((axis, grab)) => {
+          let thumb_start = match axis {
+            Axis::Horizontal => pos.x - grab,
+            Axis::Vertical => pos.y - grab,
+          };
+          self.drag_thumb(axis, thumb_start);
+        }
+        None => (),
+      },
+      // Scrolling up moves the content down
+      Event::Scroll(delta, _) => {
+        let old_offset = self.offset();
+        self.scroll_by(Vec2((-delta.x * SCROLL_STEP).round() as i32, (-delta.y * SCROLL_STEP).round() as i32));
+        // Panels this one is in only scroll once it can't go any further
+        if self.offset() != old_offset {
+          window.stop_propagation();
+        }
+      }
+      _ => (),
+    }
+  }
+}
+
+/// Keeps track of where the dividers of a `Splitter` are
+pub struct SplitterState {
+  id: Id,
+  pub axis: Axis,
+  /// The thickness of each divider
+  pub divider_size: i32,
+  pub divider_color: Color<f32>,
+  // The fraction of the available space each child gets; these sum to 1. This is reset
+  // when the number of children changes.
+  fractions: Vec<f64>,
+  // Set when the dividers move, so the splitter is laid out again
+  changed: bool,
+  // From the last layout: the size of each child, the start of each divider relative to the
+  // splitter, and the min size of each child
+  laid_out: RefCell<(Vec<i32>, Vec<i32>, Vec<i32>)>,
+  // The divider being dragged, and where it was grabbed relative to its start
+  dragging: Option<(usize, i32)>,
+}
+
+impl SplitterState {
+  pub fn new(axis: Axis, divider_size: i32, divider_color: Color<f32>) -> SplitterState {
+    SplitterState{id: next_id(), axis: axis, divider_size: divider_size, divider_color: divider_color,
+      fractions: vec![], changed: false, laid_out: RefCell::new((vec![], vec![], vec![])), dragging: None}
+  }
+
+  /// The fraction of the space (not counting dividers) that each child gets
+  pub fn fractions(&self) -> &Vec<f64> {&self.fractions}
+
+  /// Moves the dividers so each child gets the given fraction of the space; this is still
+  /// limited by the children's min sizes
+  pub fn set_fractions(&mut self, fractions: Vec<f64>) {
+    let total = fractions.iter().fold(0.0, |a, &b| a + b);
+    self.fractions = fractions.into_iter().map(|fraction| if total > 0.0 {fraction / total} else {0.0}).collect();
+    self.changed = true;
+  }
+
+  fn dividers_size(&self, num_children: usize) -> i32 {
+    if num_children == 0 {0} else {self.divider_size * (num_children - 1) as i32}
+  }
+
+  // The size of each child along the axis when the splitter is `total` long
+  fn child_sizes(&self, children: &Vec<LayoutMinSize>, total: i32) -> Vec<i32> {
+    let n = children.len();
+    let avail = total - self.dividers_size(n);
+    let mut sizes: Vec<i32> = range(0, n).map(|i| {
+      let fraction = if self.fractions.len() == n {self.fractions[i]} else {1.0 / n as f64};
+      let min = self.axis.main(children[i].min_size());
+      cmp::max((avail as f64 * fraction).floor() as i32, min)
+    }).collect();
+    // Growing children to their min sizes may have made them too big, so take the
+    // difference from the last children that can spare it; rounding leftovers go to the last child
+    let mut excess = sizes.iter().fold(0, |a, &b| a + b) - avail;
+    for i in range(0, n).rev() {
+      let min = self.axis.main(children[i].min_size());
+      let amount = if excess > 0 {cmp::min(excess, sizes[i] - min)} else {excess};
+      sizes[i] -= amount;
+      excess -= amount;
+    }
+    sizes
+  }
+
+  // Moves a divider so it starts at `start`, resizing the children on either side of it.
+  // Returns false if the divider is gone because children were removed.
+  fn drag_divider(&mut self, divider: usize, start: i32) -> bool {
+    let (mut sizes, starts, mins) = self.laid_out.borrow().clone();
+    if divider >= starts.len() || divider + 1 >= sizes.len() || divider + 1 >= mins.len() {
+      return false;
+    }
+    let first = starts[divider] - sizes[divider];
+    let combined = sizes[divider] + sizes[divider+1];
+    let before = cmp::max(cmp::min(start - first, combined - mins[divider+1]), mins[divider]);
+    sizes[divider] = before;
+    // If the children are squeezed below their min sizes, the second one gets what's left
+    sizes[divider+1] = cmp::max(combined - before, 0);
+    let total = sizes.iter().fold(0, |a, &b| a + b);
+    if total > 0 {
+      self.fractions = sizes.iter().map(|&size| size as f64 / total as f64).collect();
+      self.changed = true;
+    }
+    true
+  }
+}
+
+impl Widget for SplitterState {
+  fn id(&self) -> Id {self.id}
+
+  fn draw(&mut self, pos: Vec2<i32>, size: Vec2<i32>, window: &mut GUIWindow) {
+    let axis = self.axis;
+    for &start in self.laid_out.borrow().1.iter() {
+      window.fill_rect(pos + axis.vec(start, 0), axis.vec(self.divider_size, axis.cross(size)), self.divider_color);
+    }
+  }
+
+  fn handle_event(&mut self, event: Event, window: &mut GUIWindow) {
+    let axis = self.axis;
+    match event {
+      Event::MouseButton(glfw::MouseButtonLeft, Action::Press, _, pos) => {
+        let starts = self.laid_out.borrow().1.clone();
+        for (i, &start) in starts.iter().enumerate() {
+          let offset = axis.main(pos) - start;
+          if offset >= 0 && offset < self.divider_size {
+            self.dragging = Some((i, offset));
+            window.capture_pointer(self.id);
+          }
+        }
+      }
+      Event::MouseButton(glfw::MouseButtonLeft, Action::Release, _, _) => if self.dragging.is_some() {
+        self.dragging = None;
+        window.release_pointer();
+      },
+      Event::MouseMove(pos, _) => match self.dragging {
+        Some((divider, grab)) => if !self.drag_divider(divider, axis.main(pos) - grab) {
+          self.dragging = None;
+          window.release_pointer();
+        },
+        None => (),
+      },
+      _ => (),
+    }
+  }
+}
+
+/// The strip of tabs shown by a `Tabs` layout, which keeps track of the selected tab
+pub struct TabState {
+  id: Id,
+  font: FontKey,
+  pub text_color: Color<f32>,
+  /// The background of the selected tab
+  pub selected_color: Color<f32>,
+  // The index of the child that's shown
+  selected: usize,
+  titles: Vec<String>,
+  // The tab that was selected the last time the layout was done
+  shown: Option<usize>,
+  // The direction the tabs ran in the last time the layout was done
+  direction: Direction,
+  dirty: bool,
+}
+
+// The space between a tab's title and its edges
+const TAB_PADDING: i32 = 8;
+
+impl TabState {
+  pub fn new(font: FontKey, text_color: Color<f32>, selected_color: Color<f32>) -> TabState {
+    TabState{id: next_id(), font: font, text_color: text_color, selected_color: selected_color,
+      selected: 0, titles: vec![], shown: None, direction: Direction::LeftToRight, dirty: true}
+  }
+
+  /// The index of the child that's shown
+  pub fn selected(&self) -> usize {self.selected}
+
+  /// Shows the child at `index`, or the last one if there aren't that many
+  pub fn select(&mut self, index: usize) {
+    // Before the first layout the titles aren't known, so it's clamped then
+    self.selected = if self.titles.is_empty() {index} else {cmp::min(index, self.titles.len() - 1)};
+  }
+
+  // Returns true if the titles changed
+  fn set_titles(&mut self, titles: Vec<&str>) -> bool {
+    let changed = self.titles.len() != titles.len() ||
+      self.titles.iter().zip(titles.iter()).any(|(a, b)| a.as_slice() != *b);
+    if changed {
+      self.titles = titles.iter().map(|title| title.to_string()).collect();
+      self.dirty = true;
+    }
+    changed
+  }
+
+  // The width of each tab
+  fn tab_widths(&self, measure: &mut Measure) -> Vec<i32> {
+    self.titles.iter().map(|title| measure.string_width(self.font, title.as_slice()) + TAB_PADDING*2).collect()
+  }
+
+  // The start and width of each tab in a strip `avail` wide; right to left, the first tab is on
+  // the right
+  fn tab_extents(&self, measure: &mut Measure, avail: i32) -> Vec<(i32, i32)> {
+    let mut x = 0;
+    let mut extents = Vec::new();
+    for width in self.tab_widths(measure).into_iter() {
+      extents.push((self.direction.place(0, avail, x, width), width));
+      x += width;
+    }
+    extents
+  }
+}
+
+impl Widget for TabState {
+  fn id(&self) -> Id {self.id}
+
+  fn draw(&mut self, pos: Vec2<i32>, size: Vec2<i32>, window: &mut GUIWindow) {
+    let window_size = window.window_size;
+    for (i, &(x, width)) in self.tab_extents(window, size.x).iter().enumerate() {
+      if i == self.selected {
+        window.fill_rect(pos + Vec2(x, 0), Vec2(width, size.y), self.selected_color);
+      }
+      window.font(self.font).draw_string(self.titles[i].as_slice(), pos + Vec2(x + TAB_PADDING, TAB_PADDING/2), self.text_color, window_size);
+    }
+  }
+
+  fn min_size(&self, measure: &mut Measure) -> Vec2<i32> {
+    let width = self.tab_widths(measure).iter().fold(0, |a, &width| a + width);
+    Vec2(width, measure.vert_advance(self.font) + TAB_PADDING)
+  }
+
+  fn handle_event(&mut self, event: Event, window: &mut GUIWindow) {
+    match event {
+      Event::MouseButton(glfw::MouseButtonLeft, Action::Press, _, pos) => {
+        let avail = window.layout_state.widget_size(self.id).map(|size| size.x).unwrap_or(0);
+        for (i, &(x, width)) in self.tab_extents(window, avail).iter().enumerate() {
+          if pos.x >= x && pos.x < x + width {
+            self.selected = i;
+          }
+        }
+      }
+      _ => (),
+    }
+  }
+
+  fn take_dirty(&mut self) -> bool {
+    mem::replace(&mut self.dirty, false)
+  }
+}
+
+/// Used by `Cached` layouts and `Node`s to remember where they were placed
+pub struct LayoutCache {
+  invalid: bool,
+  // The position, size, and clip rectangle the layout was given last time
+  rect: Cell<Option<(Vec2<i32>, Vec2<i32>, Rect<i32>, Direction)>>,
+  // Set when the placement is forgotten, and cleared when the layout is placed again. Nothing
+  // inside a forgotten layout has been placed since, so it doesn't need forgetting again.
+  forgotten: Cell<bool>,
+  // The last width height_for_width was asked about, and the answer
+  height: Cell<Option<(i32, i32)>>,
+  // For nodes: the sizes the node had when it was last measured, the LayoutState generation
+  // they were measured in, and the last pass the node was checked for changes in
+  hints: Option<SizeHints>,
+  generation: Option<Id>,
+  polled: Option<Id>,
+}
+
+impl LayoutCache {
+  pub fn new() -> LayoutCache {
+    LayoutCache{invalid: true, rect: Cell::new(None), forgotten: Cell::new(false), height: Cell::new(None), hints: None, generation: None, polled: None}
+  }
+
+  /// Forces the layout to be redone next frame. This is needed when the structure of the
+  /// layout changes; changes to widgets are detected with `Widget::take_dirty`.
+  pub fn invalidate(&mut self) {
+    self.invalid = true;
+  }
+
+  // Remembers where the layout was placed
+  fn place(&self, rect: Option<(Vec2<i32>, Vec2<i32>, Rect<i32>, Direction)>) {
+    self.rect.set(rect);
+    self.forgotten.set(false);
+  }
+
+  // Forgets where the layout was placed. Returns false if it was already forgotten, in which
+  // case the layouts inside it don't need to be walked.
+  fn forget(&self) -> bool {
+    self.rect.set(None);
+    let forgotten = self.forgotten.get();
+    self.forgotten.set(true);
+    !forgotten
+  }
+}
+
+/// An owned version of `Layout` that's kept between frames; draw it with
+/// `GUIWindow::draw_tree`. Each frame, its widgets are asked whether they've changed with
+/// `Widget::take_dirty`, and only the panels containing ones that have are measured again.
+/// Panels that haven't changed are only arranged again if they're moved or resized.
+pub struct Node {
+  kind: NodeKind,
+  cache: LayoutCache,
+}
+
+/// The same as the variants of `Layout`, but owning their children
+pub enum NodeKind {
+  VPanel(Alignment, Spacing, Vec<(Node, f64)>),
+  HPanel(Alignment, Spacing, Vec<(Node, f64)>),
+  Grid(Vec<f64>, Vec<f64>, Vec<GridCell<Node>>),
+  OverlapPanel(Vec<(Node, Placement)>),
+  FlowPanel(Alignment, Spacing, i32, Vec<Node>),
+  Splitter(SplitterState, Vec<Node>),
+  Stack(usize, Vec<Node>),
+  Tabs(TabState, Vec<(String, Node)>),
+  Dock(Vec<(Node, DockEdge)>),
+  Directed(Direction, Box<Node>),
+  ScrollPanel(ScrollState, Box<Node>),
+  /// Use an `Rc<RefCell<T>>` for widgets that other code needs to access
+  Widget(Box<Widget + 'static>),
+}
+
+impl NodeKind {
+  // A layout of the node's children, each of which is a Tree
+  fn as_layout<'a>(&'a mut self) -> Layout<'a> {
+    match *self {
+      NodeKind::Widget(ref mut widget) => LWidget(&mut **widget),
+      NodeKind::VPanel(align, spacing, ref mut children) =>
+        VPanel(align, spacing, children.iter_mut().map(|&mut (ref mut child, flex)| (child.as_layout(), flex)).collect()),
+      NodeKind::HPanel(align, spacing, ref mut children) =>
+        HPanel(align, spacing, children.iter_mut().map(|&mut (ref mut child, flex)| (child.as_layout(), flex)).collect()),
+      NodeKind::Grid(ref row_flexes, ref col_flexes, ref mut cells) =>
+        Grid(row_flexes.clone(), col_flexes.clone(), cells.iter_mut().map(|cell| GridCell{layout: cell.layout.as_layout(),
+          row: cell.row, col: cell.col, row_span: cell.row_span, col_span: cell.col_span,
+          h_align: cell.h_align, v_align: cell.v_align}).collect()),
+      NodeKind::OverlapPanel(ref mut children) =>
+        OverlapPanel(children.iter_mut().map(|&mut (ref mut child, placement)| (child.as_layout(), placement)).collect()),
+      NodeKind::FlowPanel(align, spacing, line_spacing, ref mut children) =>
+        FlowPanel(align, spacing, line_spacing, children.iter_mut().map(|child| child.as_layout()).collect()),
+      NodeKind::ScrollPanel(ref mut state, ref mut child) => ScrollPanel(state, Box::new(child.as_layout())),
+      NodeKind::Splitter(ref mut state, ref mut children) =>
+        Splitter(state, children.iter_mut().map(|child| child.as_layout()).collect()),
+      NodeKind::Stack(index, ref mut children) =>
+        Stack(index, children.iter_mut().map(|child| child.as_layout()).collect()),
+      NodeKind::Tabs(ref mut state, ref mut children) =>
+        Tabs(state, children.iter_mut().map(|&mut (ref title, ref mut child)| (title.as_slice(), child.as_layout())).collect()),
+      NodeKind::Dock(ref mut children) =>
+        Dock(children.iter_mut().map(|&mut (ref mut child, edge)| (child.as_layout(), edge)).collect()),
+      NodeKind::Directed(direction, ref mut child) => Directed(direction, Box::new(child.as_layout())),
+    }
+  }
+}
+
+impl Node {
+  pub fn new(kind: NodeKind) -> Node {
+    Node{kind: kind, cache: LayoutCache::new()}
+  }
+
+  pub fn widget<W: Widget + 'static>(widget: W) -> Node {
+    Node::new(NodeKind::Widget(Box::new(widget)))
+  }
+
+  pub fn kind(&self) -> &NodeKind {&self.kind}
+
+  /// Allows changing the node's children; the node is laid out again next frame
+  pub fn kind_mut(&mut self) -> &mut NodeKind {
+    self.cache.invalidate();
+    &mut self.kind
+  }
+
+  /// Finds a widget in this node or its descendants
+  pub fn widget_mut(&mut self, id: Id) -> Option<&mut (Widget + 'static)> {
+    match self.kind {
+      NodeKind::Widget(ref mut widget) => if widget.id() == id {Some(&mut **widget)} else {None},
+      NodeKind::VPanel(_, _, ref mut children) | NodeKind::HPanel(_, _, ref mut children) =>
+        children.iter_mut().filter_map(|&mut (ref mut child, _)| child.widget_mut(id)).next(),
+      NodeKind::Grid(_, _, ref mut cells) =>
+        cells.iter_mut().filter_map(|cell| cell.layout.widget_mut(id)).next(),
+      NodeKind::OverlapPanel(ref mut children) =>
+        children.iter_mut().filter_map(|&mut (ref mut child, _)| child.widget_mut(id)).next(),
+      NodeKind::FlowPanel(_, _, _, ref mut children) | NodeKind::Splitter(_, ref mut children) |
+      NodeKind::Stack(_, ref mut children) =>
+        children.iter_mut().filter_map(|child| child.widget_mut(id)).next(),
+      NodeKind::Tabs(_, ref mut children) =>
+        children.iter_mut().filter_map(|&mut (_, ref mut child)| child.widget_mut(id)).next(),
+      NodeKind::Dock(ref mut children) =>
+        children.iter_mut().filter_map(|&mut (ref mut child, _)| child.widget_mut(id)).next(),
+      NodeKind::ScrollPanel(_, ref mut child) | NodeKind::Directed(_, ref mut child) => child.widget_mut(id),
+    }
+  }
+
+  /// A layout borrowing this tree, for drawing it
+  pub fn as_layout<'a>(&'a mut self) -> Layout<'a> {
+    Tree(self)
+  }
+
+  fn is_widget(&self) -> bool {
+    match self.kind {
+      NodeKind::Widget(_) => true,
+      _ => false,
+    }
+  }
+
+  // Calls `f` on each of the node's children, including hidden ones
+  fn for_each_child<F: FnMut(&mut Node)>(&mut self, mut f: F) {
+    match self.kind {
+      NodeKind::Widget(_) => (),
+      NodeKind::VPanel(_, _, ref mut children) | NodeKind::HPanel(_, _, ref mut children) =>
+        for &mut (ref mut child, _) in children.iter_mut() {f(child)},
+      NodeKind::Grid(_, _, ref mut cells) => for cell in cells.iter_mut() {f(&mut cell.layout)},
+      NodeKind::OverlapPanel(ref mut children) => for &mut (ref mut child, _) in children.iter_mut() {f(child)},
+      NodeKind::FlowPanel(_, _, _, ref mut children) | NodeKind::Splitter(_, ref mut children) |
+      NodeKind::Stack(_, ref mut children) => for child in children.iter_mut() {f(child)},
+      NodeKind::Tabs(_, ref mut children) => for &mut (_, ref mut child) in children.iter_mut() {f(child)},
+      NodeKind::Dock(ref mut children) => for &mut (ref mut child, _) in children.iter_mut() {f(child)},
+      NodeKind::ScrollPanel(_, ref mut child) | NodeKind::Directed(_, ref mut child) => f(&mut **child),
+    }
+  }
+
+  // Returns whether anything in the node has changed since it was last measured, and marks
+  // the nodes that have so they're measured again. Widgets that say they've changed are
+  // remembered in the state, since take_dirty only says so once. Each node is only checked
+  // once per pass.
+  fn poll_changes(&mut self, state: &mut LayoutState) -> bool {
+    match self.kind {
+      NodeKind::Widget(ref mut widget) => {
+        if widget.take_dirty() {
+          state.dirty_widgets.insert(widget.id());
+          return true;
+        }
+        return false;
+      }
+      _ => (),
+    }
+    if self.cache.polled == Some(state.pass) {
+      return self.cache.invalid;
+    }
+    let mut changed = self.cache.invalid || self.cache.generation != Some(state.generation);
+    match self.kind {
+      NodeKind::ScrollPanel(ref scroll, _) => if scroll.laid_out_offset.get() != Some(scroll.offset()) {
+        changed = true;
+      },
+      NodeKind::Splitter(ref splitter, _) => if splitter.changed {
+        changed = true;
+      },
+      NodeKind::Tabs(ref tabs, ref children) => {
+        let same_titles = tabs.titles.len() == children.len() &&
+          tabs.titles.iter().zip(children.iter()).all(|(a, &(ref b, _))| a == b);
+        // The strip is mirrored when the direction it's in changes
+        if !same_titles || tabs.shown != Some(tabs.selected) || tabs.direction != state.direction {
+          changed = true;
+        }
+      }
+      _ => (),
+    }
+    // The children of a Directed node are in its direction
+    let outer = state.direction;
+    match self.kind {
+      NodeKind::Directed(direction, _) => state.direction = direction,
+      _ => (),
+    }
+    self.for_each_child(|child| if child.poll_changes(state) {changed = true});
+    state.direction = outer;
+    self.cache.polled = Some(state.pass);
+    if changed {
+      self.cache.invalid = true;
+    }
+    changed
+  }
+
+  // Measures the node's children and wraps them in its cache. `changed` is whether anything
+  // in the node has changed since it was last measured.
+  fn measure<'a>(&'a mut self, changed: bool, cx: &mut LayoutContext) -> LayoutMinSize<'a> {
+    let Node{ref mut kind, ref mut cache} = *self;
+    match kind.as_layout() {
+      // Widgets keep their sizes in the state instead
+      LWidget(widget) => LWidget(widget).into_layout_min_size(cx),
+      layout => {
+        let child = layout.into_layout_min_size(cx);
+        if changed {
+          cache.invalid = false;
+          cache.height.set(None);
+          cache.hints = Some(child.hints());
+          cache.generation = Some(cx.state.generation);
+          // So that the caches this is nested in know something changed
+          cx.state.layout_changes += 1;
+        }
+        Cached_(cache, Box::new(child), changed)
+      }
+    }
+  }
+
+  // Makes this node and the ones in it arrange their children next time
+  fn forget_placement(&mut self) {
+    if self.cache.forget() {
+      self.for_each_child(|child| child.forget_placement());
+    }
+  }
+
+  // Collects the widgets of a node that hasn't been measured this frame, in the same order as
+  // LayoutMinSize::collect_widgets
+  fn collect_widgets<'a>(&'a mut self, widgets: &mut Vec<&'a mut (Widget + 'a)>, parents: &mut HashMap<Id, Id>, parent: Option<Id>) {
+    match self.kind {
+      NodeKind::Widget(ref mut widget) => push_widget(&mut **widget, widgets, parents, parent),
+      NodeKind::VPanel(_, _, ref mut children) | NodeKind::HPanel(_, _, ref mut children) =>
+        for &mut (ref mut child, _) in children.iter_mut() {child.collect_widgets(widgets, parents, parent)},
+      NodeKind::Grid(_, _, ref mut cells) => for cell in cells.iter_mut() {cell.layout.collect_widgets(widgets, parents, parent)},
+      NodeKind::OverlapPanel(ref mut children) =>
+        for &mut (ref mut child, _) in children.iter_mut() {child.collect_widgets(widgets, parents, parent)},
+      NodeKind::FlowPanel(_, _, _, ref mut children) => for child in children.iter_mut() {child.collect_widgets(widgets, parents, parent)},
+      NodeKind::Dock(ref mut children) =>
+        for &mut (ref mut child, _) in children.iter_mut() {child.collect_widgets(widgets, parents, parent)},
+      NodeKind::Directed(_, ref mut child) => child.collect_widgets(widgets, parents, parent),
+      NodeKind::ScrollPanel(ref mut state, ref mut child) => {
+        let id = state.id();
+        push_widget(state, widgets, parents, parent);
+        child.collect_widgets(widgets, parents, Some(id));
+      }
+      NodeKind::Splitter(ref mut state, ref mut children) => {
+        let id = state.id();
+        push_widget(state, widgets, parents, parent);
+        for child in children.iter_mut() {
+          child.collect_widgets(widgets, parents, Some(id));
+        }
+      }
+      // Hidden children don't get events
+      NodeKind::Stack(index, ref mut children) => match children.get_mut(index) {
+        Some(child) => child.collect_widgets(widgets, parents, parent),
+        None => (),
+      },
+      NodeKind::Tabs(ref mut state, ref mut children) => {
+        let selected = state.selected;
+        push_widget(state, widgets, parents, parent);
+        match children.get_mut(selected) {
+          Some(&mut (_, ref mut child)) => child.collect_widgets(widgets, parents, parent),
+          None => (),
+        }
+      }
+    }
+  }
+
+  // Draws a node that hasn't been measured this frame, where it was placed last time
+  fn draw(&mut self, widget_sizes: &HashMap<Id, Vec2<i32>>, widget_poses: &HashMap<Id, Vec2<i32>>, window: &mut GUIWindow) {
+    match self.kind {
+      NodeKind::Widget(ref mut widget) => draw_widget(&mut **widget, widget_sizes, widget_poses, window),
+      NodeKind::ScrollPanel(ref mut state, ref mut child) => {
+        let pos = *widget_poses.get(&state.id()).unwrap();
+        let size = *widget_sizes.get(&state.id()).unwrap();
+        window.push_clip(Rect(pos, pos + state.viewport(size)));
+        child.draw(widget_sizes, widget_poses, window);
+        window.pop_clip();
+        state.draw(pos, size, window);
+      }
+      NodeKind::Splitter(ref mut state, ref mut children) => {
+        for child in children.iter_mut() {
+          child.draw(widget_sizes, widget_poses, window);
+        }
+        draw_widget(state, widget_sizes, widget_poses, window);
+      }
+      NodeKind::Stack(index, ref mut children) => match children.get_mut(index) {
+        Some(child) => child.draw(widget_sizes, widget_poses, window),
+        None => (),
+      },
+      NodeKind::Tabs(ref mut state, ref mut children) => {
+        draw_widget(state, widget_sizes, widget_poses, window);
+        match children.get_mut(state.selected) {
+          Some(&mut (_, ref mut child)) => child.draw(widget_sizes, widget_poses, window),
+          None => (),
+        }
+      }
+      _ => self.for_each_child(|child| child.draw(widget_sizes, widget_poses, window)),
+    }
+  }
+}
+
+// The child of a Tree_, which is only measured if it has to be arranged again
+enum TreeChild<'a> {
+  Unmeasured(&'a mut Node),
+  Measured(LayoutMinSize<'a>),
+  // Only while it's being measured
+  Measuring,
+}
+
+// Measures a Tree_'s child if it hasn't been already
+fn measure_tree<'a>(child: &RefCell<TreeChild<'a>>, cx: &mut LayoutContext) {
+  let mut child = child.borrow_mut();
+  match mem::replace(&mut *child, TreeChild::Measuring) {
+    TreeChild::Unmeasured(node) => *child = TreeChild::Measured(node.measure(false, cx)),
+    other => *child = other,
+  }
+}
+
+fn draw_widget(widget: &mut Widget, widget_sizes: &HashMap<Id, Vec2<i32>>, widget_poses: &HashMap<Id, Vec2<i32>>, window: &mut GUIWindow) {
+  let pos = *widget_poses.get(&widget.id()).unwrap();
+  let size = *widget_sizes.get(&widget.id()).unwrap();
+  widget.draw(pos, size, window);
+}
+
+/// The edge a child of a `Dock` is attached to
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum DockEdge {Top, Bottom, Left, Right, Fill}
+
+/// Where a child of an `OverlapPanel` is placed within the panel
+#[derive(Debug, Copy, Clone)]
+pub struct Placement {
+  /// If this is None, the child is stretched to fill the width of the panel; otherwise it
+  /// gets its preferred width, if there's room, and is aligned
+  pub h_align: Option<Alignment>,
+  /// If this is None, the child is stretched to fill the height of the panel; otherwise it
+  /// gets its preferred height, if there's room, and is aligned
+  pub v_align: Option<Alignment>,
+  /// Added to the child's position after it's been aligned; use negative values to move
+  /// a child away from the right or bottom edge
+  pub offset: Vec2<i32>,
+}
+
+impl Placement {
+  /// Stretches the child over the entire panel
+  pub fn fill() -> Placement {
+    Placement{h_align: None, v_align: None, offset: Vec2::zero()}
+  }
+
+  /// Gives the child its preferred size, limited to the panel but no smaller than its min
+  /// size, and aligns it within the panel; for instance,
+  /// `Placement::anchor(Trailing, Leading)` pins it to the top right corner
+  pub fn anchor(h_align: Alignment, v_align: Alignment) -> Placement {
+    Placement{h_align: Some(h_align), v_align: Some(v_align), offset: Vec2::zero()}
+  }
+
+  pub fn offset(self, offset: Vec2<i32>) -> Placement {
+    Placement{offset: offset, ..self}
+  }
+}
+
+/// A child of a `Grid`, occupying one or more rows and columns. `L` is the type of the
+/// child, which is usually a `Layout`.
+pub struct GridCell<L> {
+  pub layout: L,
+  pub row: usize,
+  pub col: usize,
+  pub row_span: usize,
+  pub col_span: usize,
+  /// If this is None, the child is stretched to fill the width of its cell; otherwise it
+  /// gets its preferred width, if there's room, and is aligned
+  pub h_align: Option<Alignment>,
+  /// If this is None, the child is stretched to fill the height of its cell; otherwise it
+  /// gets its preferred height, if there's room, and is aligned
+  pub v_align: Option<Alignment>,
+}
+
+impl<L> GridCell<L> {
+  /// Creates a cell that spans one row and one column and fills it
+  pub fn new(row: usize, col: usize, layout: L) -> GridCell<L> {
+    GridCell{layout: layout, row: row, col: col, row_span: 1, col_span: 1, h_align: None, v_align: None}
+  }
+
+  pub fn span(self, row_span: usize, col_span: usize) -> GridCell<L> {
+    GridCell{row_span: row_span, col_span: col_span, ..self}
+  }
+
+  pub fn align(self, h_align: Option<Alignment>, v_align: Option<Alignment>) -> GridCell<L> {
+    GridCell{h_align: h_align, v_align: v_align, ..self}
+  }
+
+  /// Replaces the child with the result of calling `f` on it, keeping the cell in the same place
+  pub fn map<M, F: FnOnce(L) -> M>(self, f: F) -> GridCell<M> {
+    GridCell{layout: f(self.layout), row: self.row, col: self.col, row_span: self.row_span, col_span: self.col_span,
+      h_align: self.h_align, v_align: self.v_align}
+  }
+}
+
+// The rows or columns of a grid
+#[derive(Clone)]
+struct GridTracks {
+  // These are adjusted to sum to 1
+  flexes: Vec<f64>,
+  min_sizes: Vec<i32>,
+  preferred_sizes: Vec<i32>,
+  // The largest max size of the cells that are only in each track, if it has any
+  cell_maxes: Vec<Option<i32>>,
+}
+
+impl GridTracks {
+  fn new(flexes: Vec<f64>) -> GridTracks {
+    let mut total_flex = 0.0;
+    for &flex in flexes.iter() {
+      total_flex += flex;
+    }
+    if total_flex == 0.0 {
+      total_flex = 1.0;
+    }
+    let sizes: Vec<i32> = flexes.iter().map(|_| 0).collect();
+    GridTracks{flexes: flexes.iter().map(|&flex| flex/total_flex).collect(), min_sizes: sizes.clone(), preferred_sizes: sizes,
+      cell_maxes: flexes.iter().map(|_| None).collect()}
+  }
+
+  fn len(&self) -> usize {
+    self.flexes.len()
+  }
+
+  fn min_size(&self) -> i32 {
+    self.min_sizes.iter().fold(0, |a, &b| a + b)
+  }
+
+  fn preferred_size(&self) -> i32 {
+    range(0, self.len()).fold(0, |a, i| a + self.preferred(i))
+  }
+
+  fn max_size(&self) -> i32 {
+    range(0, self.len()).fold(0, |a: i32, i| a.saturating_add(self.max(i)))
+  }
+
+  // The preferred size of a track, which is never less than its min size
+  fn preferred(&self, i: usize) -> i32 {
+    cmp::max(self.preferred_sizes[i], self.min_sizes[i])
+  }
+
+  // Like a panel's children, a track that doesn't flex never grows past its preferred size.
+  // One that flexes can grow as large as the largest cell in it, or without limit if it
+  // only has spanning cells.
+  fn max(&self, i: usize) -> i32 {
+    if self.flexes[i] > 0.0 {
+      cmp::max(self.cell_maxes[i].unwrap_or(UNBOUNDED), self.preferred(i))
+    } else {
+      self.preferred(i)
+    }
+  }
+
+  // Fits cells with the given (start, span) into the tracks' min sizes
+  fn fit_cells(&mut self, spans: Vec<(usize, usize)>, sizes: Vec<i32>) {
+    fit_track_sizes(self.min_sizes.as_mut_slice(), self.flexes.as_slice(), spans, sizes);
+  }
+
+  // Fits cells into the tracks' preferred sizes, starting from their min sizes
+  fn fit_preferred(&mut self, spans: Vec<(usize, usize)>, sizes: Vec<i32>) {
+    for i in range(0, self.len()) {
+      self.preferred_sizes[i] = cmp::max(self.preferred_sizes[i], self.min_sizes[i]);
+    }
+    fit_track_sizes(self.preferred_sizes.as_mut_slice(), self.flexes.as_slice(), spans, sizes);
+  }
+
+  // Limits how far each track can grow by the max sizes of the cells that are only in it
+  fn limit(&mut self, spans: Vec<(usize, usize)>, maxes: Vec<i32>) {
+    for (&(start, span), &max) in spans.iter().zip(maxes.iter()) {
+      if span == 1 {
+        self.cell_maxes[start] = Some(cmp::max(self.cell_maxes[start].unwrap_or(0), max));
+      }
+    }
+  }
+
+  // The size of each track when the tracks are given `size` in total
+  fn sizes(&self, size: i32) -> Vec<i32> {
+    let items: Vec<AxisItem> = range(0, self.len())
+      .map(|i| AxisItem{min: self.min_sizes[i], preferred: self.preferred(i), max: self.max(i), flex: self.flexes[i]}).collect();
+    distribute(items.as_slice(), size)
+  }
+}
+
+// Grows the tracks from start to start+span so that together they're at least `size` long.
+// The missing space is split according to the flexes of those tracks, or evenly if they
+// don't flex.
+fn fit_span(sizes: &mut [i32], flexes: &[f64], start: usize, span: usize, size: i32) {
+  let end = start + span;
+  let current = sizes[start..end].iter().fold(0, |a, &b| a + b);
+  let missing = size - current;
+  if missing <= 0 {
+    return;
+  }
+  let span_flex = flexes[start..end].iter().fold(0.0, |a, &b| a + b);
+  let mut given = 0;
+  for i in range(start, end) {
+    let share = if span_flex == 0.0 {1.0 / span as f64} else {flexes[i] / span_flex};
+    let amount = if i == end-1 {missing - given} else {(missing as f64 * share).floor() as i32};
+    sizes[i] += amount;
+    given += amount;
+  }
+}
+
+// Fits cells with the given (start, span) into track sizes. Cells spanning a single track
+// are fitted first, so spanning cells only grow tracks that would otherwise be too small
+// for them.
+fn fit_track_sizes(track_sizes: &mut [i32], flexes: &[f64], spans: Vec<(usize, usize)>, sizes: Vec<i32>) {
+  let mut order: Vec<usize> = range(0, spans.len()).collect();
+  order.sort_by(|&a, &b| spans[a].1.cmp(&spans[b].1));
+  for &i in order.iter() {
+    let (start, span) = spans[i];
+    fit_span(track_sizes, flexes, start, span, sizes[i]);
+  }
+}
+
+// Refits a grid's rows to the heights its cells need once the column widths are known
+fn fit_grid_rows(rows: &GridTracks, cells: &Vec<GridCell<LayoutMinSize>>, col_sizes: &Vec<i32>, cx: &mut LayoutContext) -> GridTracks {
+  let mut rows = rows.clone();
+  let mut heights = Vec::new();
+  for cell in cells.iter() {
+    let hints = cell.layout.hints();
+    let cell_width = sum_range(col_sizes, cell.col, cell.col + cell.col_span);
+    let (_, width) = align_axis(cell.h_align, 0, cell_width, hints.min.x, hints.preferred.x, hints.max.x);
+    heights.push(cell.layout.height_for_width(width, cx));
+  }
+  rows.fit_cells(cells.iter().map(|cell| (cell.row, cell.row_span)).collect(), heights);
+  rows
+}
+
+// The widths of an HPanel's children when the panel is `width` wide, including its padding
+fn hpanel_widths(spacing: &Spacing, children: &Vec<(LayoutMinSize, f64)>, width: i32) -> Vec<i32> {
+  let items: Vec<AxisItem> = children.iter().map(|&(ref child_layout, flex)| {
+    let hints = child_layout.hints();
+    AxisItem{min: hints.min.x, preferred: hints.preferred.x, max: hints.max.x, flex: flex}
+  }).collect();
+  distribute(items.as_slice(), width - spacing.padding.size().x - spacing.total_between(children.len()))
+}
+
+fn intersect_rects(a: Rect<i32>, b: Rect<i32>) -> Rect<i32> {
+  let start = a.start.component_max(b.start);
+  let end = Vec2(cmp::min(a.end.x, b.end.x), cmp::min(a.end.y, b.end.y)).component_max(start);
+  Rect(start, end)
+}
+
+fn rect_contains(rect: Rect<i32>, pos: Vec2<i32>) -> bool {
+  pos.x >= rect.start.x && pos.y >= rect.start.y && pos.x < rect.end.x && pos.y < rect.end.y
+}
+
+fn push_widget<'a>(widget: &'a mut (Widget + 'a), widgets: &mut Vec<&'a mut (Widget + 'a)>, parents: &mut HashMap<Id, Id>, parent: Option<Id>) {
+  match parent {
+    Some(parent) => {parents.insert(widget.id(), parent);}
+    None => (),
+  }
+  widgets.push(widget);
+}
+
+// The height a Dock's children need when it's `width` wide
+fn dock_height_for_width(children: &[(LayoutMinSize, DockEdge)], width: i32, cx: &mut LayoutContext) -> i32 {
+  match children.first() {
+    None => 0,
+    Some(&(ref child, edge)) => {
+      let rest = &children[1..];
+      match edge {
+        DockEdge::Top | DockEdge::Bottom =>
+          child.height_for_width(width, cx) + dock_height_for_width(rest, width, cx),
+        DockEdge::Left | DockEdge::Right => {
+          let hints = child.hints();
+          let child_width = cmp::max(cmp::min(hints.preferred.x, width), hints.min.x);
+          cmp::max(child.height_for_width(child_width, cx), dock_height_for_width(rest, width - child_width, cx))
+        }
+        DockEdge::Fill => cmp::max(child.height_for_width(width, cx), dock_height_for_width(rest, width, cx)),
       }
     }
   }
 }
 
+// A line of a FlowPanel
+struct FlowLine {
+  // The index and size of each child on the line
+  children: Vec<(usize, Vec2<i32>)>,
+  width: i32,
+  height: i32,
+}
+
+// Breaks a FlowPanel's children into lines, when the panel is `width` wide including padding
+fn flow_lines(spacing: &Spacing, children: &Vec<LayoutMinSize>, width: i32, cx: &mut LayoutContext) -> Vec<FlowLine> {
+  let width = width - spacing.padding.size().x;
+  let mut lines = Vec::new();
+  let mut line = FlowLine{children: Vec::new(), width: 0, height: 0};
+  for (i, child) in children.iter().enumerate() {
+    let child_width = cmp::max(cmp::min(child.hints().preferred.x, width), child.min_size().x);
+    let child_size = Vec2(child_width, child.height_for_width(child_width, cx));
+    if !line.children.is_empty() && line.width + spacing.between + child_size.x > width {
+      lines.push(mem::replace(&mut line, FlowLine{children: Vec::new(), width: 0, height: 0}));
+    }
+    if !line.children.is_empty() {
+      line.width += spacing.between;
+    }
+    line.width += child_size.x;
+    line.height = cmp::max(line.height, child_size.y);
+    line.children.push((i, child_size));
+  }
+  if !line.children.is_empty() {
+    lines.push(line);
+  }
+  lines
+}
+
+fn retain_ids<T>(map: &mut HashMap<Id, T>, ids: &HashSet<Id>) {
+  let stale: Vec<Id> = map.keys().filter(|id| !ids.contains(*id)).map(|&id| id).collect();
+  for id in stale.iter() {
+    map.remove(id);
+  }
+}
+
+fn sum_range(sizes: &Vec<i32>, start: usize, end: usize) -> i32 {
+  sizes[start..end].iter().fold(0, |a, &b| a + b)
+}
+
+// Places a child along one axis of the space it's been given. If it isn't aligned, it's
+// stretched as far as its max size allows; otherwise it gets its preferred size.
+fn align_axis(align: Option<Alignment>, pos: i32, avail: i32, min_size: i32, preferred: i32, max: i32) -> (i32, i32) {
+  let size = match align {
+    None => cmp::max(cmp::min(avail, max), min_size),
+    Some(_) => cmp::max(cmp::min(avail, preferred), min_size),
+  };
+  match align {
+    None | Some(Leading) => (pos, size),
+    Some(Center) => (pos + (avail-size)/2, size),
+    Some(Trailing) => (pos + avail - size, size),
+  }
+}
+
 enum LayoutMinSize<'a> {
-  VPanel_(Alignment, Vec<(LayoutMinSize<'a>, f64)>, Vec2<i32>),
-  HPanel_(Alignment, Vec<(LayoutMinSize<'a>, f64)>, Vec2<i32>),
-  OverlapPanel_(Vec<LayoutMinSize<'a>>, Vec2<i32>),
-  LWidget_(&'a mut (Widget + 'a), Vec2<i32>),
+  VPanel_(Alignment, Spacing, Vec<(LayoutMinSize<'a>, f64)>, SizeHints),
+  HPanel_(Alignment, Spacing, Vec<(LayoutMinSize<'a>, f64)>, SizeHints),
+  OverlapPanel_(Vec<(LayoutMinSize<'a>, Placement)>, SizeHints),
+  FlowPanel_(Alignment, Spacing, i32, Vec<LayoutMinSize<'a>>, SizeHints),
+  // Rows, columns, cells
+  Grid_(GridTracks, GridTracks, Vec<GridCell<LayoutMinSize<'a>>>, SizeHints),
+  ScrollPanel_(&'a mut ScrollState, Box<LayoutMinSize<'a>>, SizeHints),
+  // The bool is whether anything in the child has changed since it was last laid out
+  Cached_(&'a mut LayoutCache, Box<LayoutMinSize<'a>>, bool),
+  // A node that hasn't changed, with the sizes it had last time
+  Tree_(RefCell<TreeChild<'a>>, SizeHints),
+  Splitter_(&'a mut SplitterState, Vec<LayoutMinSize<'a>>, SizeHints),
+  Stack_(usize, Vec<LayoutMinSize<'a>>, SizeHints),
+  Dock_(Vec<(LayoutMinSize<'a>, DockEdge)>, SizeHints),
+  Directed_(Direction, Box<LayoutMinSize<'a>>),
+  LWidget_(&'a mut (Widget + 'a), SizeHints),
 }
 
 impl<'a> LayoutMinSize<'a> {
   // This is very fast because it's computed in advance
-  fn min_size(&self) -> Vec2<i32> {
+  fn hints(&self) -> SizeHints {
     match *self {
-      VPanel_(_, _, min_size) => min_size,
-      HPanel_(_, _, min_size) => min_size,
-      OverlapPanel_(_, min_size) => min_size,
-      LWidget_(_, min_size) => min_size,
+      VPanel_(_, _, _, hints) => hints,
+      HPanel_(_, _, _, hints) => hints,
+      OverlapPanel_(_, hints) => hints,
+      FlowPanel_(_, _, _, _, hints) => hints,
+      Grid_(_, _, _, hints) => hints,
+      ScrollPanel_(_, _, hints) => hints,
+      Cached_(_, ref child, _) => child.hints(),
+      Tree_(_, hints) => hints,
+      Splitter_(_, _, hints) => hints,
+      Stack_(_, _, hints) => hints,
+      Dock_(_, hints) => hints,
+      Directed_(_, ref child) => child.hints(),
+      LWidget_(_, hints) => hints,
     }
   }
 
-  fn collect_widgets(self, widgets: &mut Vec<&'a mut (Widget + 'a)>) {
+  fn min_size(&self) -> Vec2<i32> {
+    self.hints().min
+  }
+
+  // Collects the widgets in the order they're hit tested, which is the order they're drawn
+  // in, except that containers like ScrollState come before their contents. Widgets later in
+  // the list are on top. `parents` maps each widget to the container it's in.
+  fn collect_widgets(self, widgets: &mut Vec<&'a mut (Widget + 'a)>, parents: &mut HashMap<Id, Id>, parent: Option<Id>) {
     match self {
-      LWidget_(widget, _) => widgets.push(widget),
-      VPanel_(_, children, _) => {
+      LWidget_(widget, _) => push_widget(widget, widgets, parents, parent),
+      VPanel_(_, _, children, _) => {
         for (child,_) in children.into_iter() {
-          child.collect_widgets(widgets);
+          child.collect_widgets(widgets, parents, parent);
         }
       }
-      HPanel_(_, children, _) => {
+      HPanel_(_, _, children, _) => {
         for (child,_) in children.into_iter() {
-          child.collect_widgets(widgets);
+          child.collect_widgets(widgets, parents, parent);
+        }
+      }
+      FlowPanel_(_, _, _, children, _) => {
+        for child in children.into_iter() {
+          child.collect_widgets(widgets, parents, parent);
         }
       }
       OverlapPanel_(children, _) => {
+        for (child,_) in children.into_iter() {
+          child.collect_widgets(widgets, parents, parent);
+        }
+      }
+      Grid_(_, _, cells, _) => {
+        for cell in cells.into_iter() {
+          cell.layout.collect_widgets(widgets, parents, parent);
+        }
+      }
+      ScrollPanel_(state, child, _) => {
+        let id = state.id();
+        push_widget(state, widgets, parents, parent);
+        let child = *child;
+        child.collect_widgets(widgets, parents, Some(id));
+      }
+      Cached_(_, child, _) => {
+        let child = *child;
+        child.collect_widgets(widgets, parents, parent);
+      }
+      Tree_(child, _) => match child.into_inner() {
+        TreeChild::Unmeasured(node) => node.collect_widgets(widgets, parents, parent),
+        TreeChild::Measured(child) => child.collect_widgets(widgets, parents, parent),
+        TreeChild::Measuring => (),
+      },
+      Splitter_(state, children, _) => {
+        let id = state.id();
+        push_widget(state, widgets, parents, parent);
         for child in children.into_iter() {
-          child.collect_widgets(widgets);
+          child.collect_widgets(widgets, parents, Some(id));
+        }
+      }
+      Dock_(children, _) => {
+        for (child,_) in children.into_iter() {
+          child.collect_widgets(widgets, parents, parent);
+        }
+      }
+      Directed_(_, child) => child.collect_widgets(widgets, parents, parent),
+      // Hidden children don't get events
+      Stack_(index, children, _) => match children.into_iter().nth(index) {
+        Some(child) => child.collect_widgets(widgets, parents, parent),
+        None => (),
+      },
+    }
+  }
+
+  // The smallest height this layout can have when it's `width` wide. This is only larger than
+  // the min height if something in it wraps, like a wrapped LabelWidget.
+  fn height_for_width(&self, width: i32, cx: &mut LayoutContext) -> i32 {
+    let height = match *self {
+      LWidget_(ref widget, _) => widget.height_for_width(width, cx.measure),
+      VPanel_(_, ref spacing, ref children, _) => {
+        let inner_width = width - spacing.padding.size().x;
+        let mut height = spacing.total_between(children.len());
+        for &(ref child_layout, _) in children.iter() {
+          let child_width = child_layout.hints().clamp(Vec2(inner_width, 0)).x;
+          height += child_layout.height_for_width(child_width, cx);
+        }
+        height + spacing.padding.size().y
+      }
+      HPanel_(_, ref spacing, ref children, _) => {
+        let widths = hpanel_widths(spacing, children, width);
+        let mut height = 0;
+        for (&(ref child_layout, _), &child_width) in children.iter().zip(widths.iter()) {
+          height = cmp::max(height, child_layout.height_for_width(child_width, cx));
+        }
+        height + spacing.padding.size().y
+      }
+      FlowPanel_(_, ref spacing, line_spacing, ref children, _) => {
+        let lines = flow_lines(spacing, children, width, cx);
+        let mut height = 0;
+        for line in lines.iter() {
+          height += line.height;
+        }
+        if lines.len() > 0 {
+          height += line_spacing * (lines.len() - 1) as i32;
+        }
+        height + spacing.padding.size().y
+      }
+      OverlapPanel_(ref children, _) => {
+        let mut height = 0;
+        for &(ref child, ref placement) in children.iter() {
+          let hints = child.hints();
+          let (_, child_width) = align_axis(placement.h_align, 0, width, hints.min.x, hints.preferred.x, hints.max.x);
+          height = cmp::max(height, child.height_for_width(child_width, cx) + placement.offset.y.abs());
+        }
+        height
+      }
+      Grid_(ref rows, ref cols, ref cells, _) => {
+        let col_sizes = cols.sizes(width);
+        fit_grid_rows(rows, cells, &col_sizes, cx).min_size()
+      }
+      ScrollPanel_(ref state, ref child, _) => {
+        if state.vertical {
+          0
+        } else {
+          let bars = state.bars_size();
+          child.height_for_width(width - bars.x, cx) + bars.y
+        }
+      }
+      // The height is kept until something in the child changes
+      Cached_(ref cache, ref child, _) => match cache.height.get() {
+        Some((cached_width, height)) if cached_width == width => height,
+        _ => {
+          let height = child.height_for_width(width, cx);
+          cache.height.set(Some((width, height)));
+          height
+        }
+      },
+      Tree_(ref child, _) => {
+        let cached = match *child.borrow() {
+          TreeChild::Unmeasured(ref node) => match node.cache.height.get() {
+            Some((cached_width, height)) if cached_width == width => Some(height),
+            _ => None,
+          },
+          _ => None,
+        };
+        match cached {
+          Some(height) => height,
+          None => {
+            measure_tree(child, cx);
+            match *child.borrow() {
+              TreeChild::Measured(ref child) => child.height_for_width(width, cx),
+              _ => 0,
+            }
+          }
         }
       }
+      Directed_(_, ref child) => child.height_for_width(width, cx),
+      Stack_(index, ref children, _) => match children.get(index) {
+        Some(child) => child.height_for_width(width, cx),
+        None => 0,
+      },
+      Dock_(ref children, _) => dock_height_for_width(children.as_slice(), width, cx),
+      Splitter_(ref state, ref children, _) => match state.axis {
+        Axis::Horizontal => {
+          let widths = state.child_sizes(children, width);
+          let mut height = 0;
+          for (child, &child_width) in children.iter().zip(widths.iter()) {
+            height = cmp::max(height, child.height_for_width(child_width, cx));
+          }
+          height
+        }
+        Axis::Vertical => {
+          let mut height = state.dividers_size(children.len());
+          for child in children.iter() {
+            height += child.height_for_width(width, cx);
+          }
+          height
+        }
+      },
+    };
+    cmp::max(height, self.min_size().y)
+  }
+
+  // Makes every Cached layout and node in this one arrange its child again next time
+  fn forget_placement(&self) {
+    match *self {
+      LWidget_(_, _) => (),
+      VPanel_(_, _, ref children, _) | HPanel_(_, _, ref children, _) =>
+        for &(ref child, _) in children.iter() {child.forget_placement()},
+      OverlapPanel_(ref children, _) => for &(ref child, _) in children.iter() {child.forget_placement()},
+      FlowPanel_(_, _, _, ref children, _) | Splitter_(_, ref children, _) | Stack_(_, ref children, _) =>
+        for child in children.iter() {child.forget_placement()},
+      Grid_(_, _, ref cells, _) => for cell in cells.iter() {cell.layout.forget_placement()},
+      Dock_(ref children, _) => for &(ref child, _) in children.iter() {child.forget_placement()},
+      ScrollPanel_(_, ref child, _) | Directed_(_, ref child) => child.forget_placement(),
+      Cached_(ref cache, ref child, _) => if cache.forget() {child.forget_placement()},
+      Tree_(ref child, _) => match *child.borrow_mut() {
+        TreeChild::Unmeasured(ref mut node) => node.forget_placement(),
+        TreeChild::Measured(ref child) => child.forget_placement(),
+        TreeChild::Measuring => (),
+      },
     }
   }
 
   // The 'pos' parameter is the position of the top-level layout widget; it must calculate the positions of its children and call calc_pos for each of them
   // TODO: this should probably set a Rect/AABB or something instead of setting the position and size separately
-  fn calc_pos_size(&self, pos: Vec2<i32>, size: Vec2<i32>, widget_poses: &mut HashMap<Id, Vec2<i32>>, widget_sizes: &mut HashMap<Id, Vec2<i32>>) {
+  // 'clip' is the part of the window the layout is visible in
+  fn calc_pos_size(&self, pos: Vec2<i32>, size: Vec2<i32>, clip: Rect<i32>, cx: &mut LayoutContext) {
     match *self {
-      LWidget_(ref widget, ref min_size) => {
+      LWidget_(ref widget, ref hints) => {
+        let min_size = Vec2(hints.min.x, self.height_for_width(size.x, cx));
         let real_size = min_size.component_max(size);
         if real_size != size {
           println!("Warning: widget is larger than allocated size; its contents may overlap adjacent widgets.");
         }
-        widget_sizes.insert(widget.id(), real_size);
-        widget_poses.insert(widget.id(), pos);
+        cx.state.widget_sizes.insert(widget.id(), real_size);
+        cx.state.widget_poses.insert(widget.id(), pos);
+        cx.state.widget_clips.insert(widget.id(), clip);
       }
-      VPanel_(ref align, ref children, ref min_size) => {
-        let extra_space = size.y - min_size.y;
-        let mut child_sizes = Vec::new();
-        for &(ref child_layout, flex) in children.iter() {
-          child_sizes.push(Vec2(size.x, child_layout.min_size().y + (extra_space as f64*flex).floor() as i32));
+      VPanel_(ref align, ref spacing, ref children, _) => {
+        let (pos, size) = spacing.padding.inset(pos, size);
+        // The widths are known first, so the heights can depend on them
+        let widths: Vec<i32> = children.iter().map(|&(ref child_layout, _)| child_layout.hints().clamp(size).x).collect();
+        let mut items = Vec::new();
+        for (&(ref child_layout, flex), &width) in children.iter().zip(widths.iter()) {
+          let hints = child_layout.hints();
+          let min_height = child_layout.height_for_width(width, cx);
+          items.push(AxisItem{min: min_height, preferred: cmp::max(hints.preferred.y, min_height), max: hints.max.y, flex: flex});
         }
+        let heights = distribute(items.as_slice(), size.y - spacing.total_between(children.len()));
+        let child_sizes: Vec<Vec2<i32>> = widths.iter().zip(heights.iter()).map(|(&width, &height)| Vec2(width, height)).collect();
 
         let mut pos = pos;
         for i in range(0, children.len()) {
           let (ref child_layout, _) = children[i];
           let child_size = child_sizes[i];
           let wiggle_room = size.x - child_size.x;
-          let x_pos = match *align {
+          let x_pos = match cx.state.direction.resolve(*align) {
             Leading => pos.x,
             Center => pos.x + wiggle_room/2,
             Trailing => pos.x + wiggle_room,
           };
-          child_layout.calc_pos_size(Vec2(x_pos, pos.y), child_size, widget_poses, widget_sizes);
-          pos.y += child_size.y;
+          child_layout.calc_pos_size(Vec2(x_pos, pos.y), child_size, clip, cx);
+          pos.y += child_size.y + spacing.between;
         }
       }
-      HPanel_(ref align, ref children, ref min_size) => {
-        let extra_space = size.x - min_size.x;
+      HPanel_(ref align, ref spacing, ref children, _) => {
+        let widths = hpanel_widths(spacing, children, size.x);
+        let (pos, size) = spacing.padding.inset(pos, size);
         let mut child_sizes = Vec::new();
-        for &(ref child_layout, flex) in children.iter() {
-          child_sizes.push(Vec2(child_layout.min_size().x + (extra_space as f64*flex).floor() as i32, size.y));
+        for (&(ref child_layout, _), &width) in children.iter().zip(widths.iter()) {
+          let height = cmp::max(child_layout.hints().clamp(size).y, child_layout.height_for_width(width, cx));
+          child_sizes.push(Vec2(width, height));
         }
 
-        let mut pos = pos;
+        let direction = cx.state.direction;
+        let mut x = 0;
         for i in range(0, children.len()) {
           let (ref child_layout, _) = children[i];
           let child_size = child_sizes[i];
@@ -216,34 +2096,226 @@ impl<'a> LayoutMinSize<'a> {
             Center => pos.y + wiggle_room/2,
             Trailing => pos.y + wiggle_room,
           };
-          child_layout.calc_pos_size(Vec2(pos.x, y_pos), child_size, widget_poses, widget_sizes);
-          pos.x += child_size.x;
+          let x_pos = direction.place(pos.x, size.x, x, child_size.x);
+          child_layout.calc_pos_size(Vec2(x_pos, y_pos), child_size, clip, cx);
+          x += child_size.x + spacing.between;
+        }
+      }
+      FlowPanel_(ref align, ref spacing, line_spacing, ref children, _) => {
+        let lines = flow_lines(spacing, children, size.x, cx);
+        let (pos, size) = spacing.padding.inset(pos, size);
+        let direction = cx.state.direction;
+        let mut y = pos.y;
+        for line in lines.iter() {
+          // The offset from the start of the line, which is on the right in RTL
+          let wiggle_room = size.x - line.width;
+          let mut x = match *align {
+            Leading => 0,
+            Center => wiggle_room/2,
+            Trailing => wiggle_room,
+          };
+          for &(i, child_size) in line.children.iter() {
+            let x_pos = direction.place(pos.x, size.x, x, child_size.x);
+            children[i].calc_pos_size(Vec2(x_pos, y), child_size, clip, cx);
+            x += child_size.x + spacing.between;
+          }
+          y += line.height + line_spacing;
+        }
+      }
+      OverlapPanel_(ref children, _) => {
+        let direction = cx.state.direction;
+        for &(ref child, ref placement) in children.iter() {
+          let hints = child.hints();
+          let h_align = placement.h_align.map(|align| direction.resolve(align));
+          let (x, w) = align_axis(h_align, pos.x, size.x, hints.min.x, hints.preferred.x, hints.max.x);
+          let min_height = child.height_for_width(w, cx);
+          let (y, h) = align_axis(placement.v_align, pos.y, size.y, min_height, cmp::max(hints.preferred.y, min_height), hints.max.y);
+          let offset = match direction {
+            Direction::LeftToRight => placement.offset,
+            Direction::RightToLeft => Vec2(-placement.offset.x, placement.offset.y),
+          };
+          child.calc_pos_size(Vec2(x, y) + offset, Vec2(w, h), clip, cx);
+        }
+      }
+      Grid_(ref rows, ref cols, ref cells, _) => {
+        let col_sizes = cols.sizes(size.x);
+        let row_sizes = fit_grid_rows(rows, cells, &col_sizes, cx).sizes(size.y);
+        let direction = cx.state.direction;
+        for cell in cells.iter() {
+          let cell_size = Vec2(sum_range(&col_sizes, cell.col, cell.col + cell.col_span),
+            sum_range(&row_sizes, cell.row, cell.row + cell.row_span));
+          let cell_x = direction.place(pos.x, size.x, sum_range(&col_sizes, 0, cell.col), cell_size.x);
+          let cell_pos = Vec2(cell_x, pos.y + sum_range(&row_sizes, 0, cell.row));
+          let hints = cell.layout.hints();
+          let h_align = cell.h_align.map(|align| direction.resolve(align));
+          let (x, w) = align_axis(h_align, cell_pos.x, cell_size.x, hints.min.x, hints.preferred.x, hints.max.x);
+          let min_height = cell.layout.height_for_width(w, cx);
+          let (y, h) = align_axis(cell.v_align, cell_pos.y, cell_size.y, min_height, cmp::max(hints.preferred.y, min_height), hints.max.y);
+          cell.layout.calc_pos_size(Vec2(x, y), Vec2(w, h), clip, cx);
+        }
+      }
+      ScrollPanel_(ref state, ref child, _) => {
+        let view_size = state.viewport(size);
+        let child_hints = child.hints();
+        let content_width = if state.horizontal {
+          cmp::max(child_hints.preferred.x, view_size.x)
+        } else {
+          view_size.x
+        };
+        let content_height = if state.vertical {
+          cmp::max(cmp::max(child.height_for_width(content_width, cx), child_hints.preferred.y), view_size.y)
+        } else {
+          view_size.y
+        };
+        state.set_sizes(view_size, Vec2(content_width, content_height));
+        state.laid_out_offset.set(Some(state.offset()));
+
+        let view_clip = intersect_rects(clip, Rect(pos, pos + view_size));
+        child.calc_pos_size(pos - state.offset(), Vec2(content_width, content_height), view_clip,
+          cx);
+        cx.state.widget_sizes.insert(state.id(), size);
+        cx.state.widget_poses.insert(state.id(), pos);
+        cx.state.widget_clips.insert(state.id(), clip);
+      }
+      Splitter_(ref state, ref children, _) => {
+        let axis = state.axis;
+        let sizes = state.child_sizes(children, axis.main(size));
+        let mut main_pos = 0;
+        let mut divider_starts = Vec::new();
+        for (i, (child, &child_main)) in children.iter().zip(sizes.iter()).enumerate() {
+          if i > 0 {
+            divider_starts.push(main_pos);
+            main_pos += state.divider_size;
+          }
+          child.calc_pos_size(pos + axis.vec(main_pos, 0), axis.vec(child_main, axis.cross(size)), clip,
+            cx);
+          main_pos += child_main;
+        }
+        *state.laid_out.borrow_mut() = (sizes, divider_starts, children.iter().map(|child| axis.main(child.min_size())).collect());
+        cx.state.widget_sizes.insert(state.id(), size);
+        cx.state.widget_poses.insert(state.id(), pos);
+        cx.state.widget_clips.insert(state.id(), clip);
+      }
+      Stack_(index, ref children, _) => {
+        for (i, child) in children.iter().enumerate() {
+          if i == index {
+            child.calc_pos_size(pos, size, clip, cx);
+          } else {
+            // Hidden widgets are forgotten by forget_missing, so their caches have to place
+            // them again when they're shown. Caches that are already forgotten stop the walk,
+            // so a hidden page is only walked once after it's hidden.
+            child.forget_placement();
+          }
+        }
+      }
+      Dock_(ref children, _) => {
+        // The space that hasn't been taken yet
+        let mut start = pos;
+        let mut end = pos + size;
+        for &(ref child, edge) in children.iter() {
+          let hints = child.hints();
+          let left = end - start;
+          match edge {
+            DockEdge::Top | DockEdge::Bottom => {
+              let min_height = child.height_for_width(left.x, cx);
+              let height = cmp::max(cmp::min(cmp::max(hints.preferred.y, min_height), left.y), min_height);
+              let child_y = if edge == DockEdge::Top {start.y} else {end.y - height};
+              child.calc_pos_size(Vec2(start.x, child_y), Vec2(left.x, height), clip, cx);
+              if edge == DockEdge::Top {start.y += height} else {end.y -= height}
+            }
+            DockEdge::Left | DockEdge::Right => {
+              let width = cmp::max(cmp::min(hints.preferred.x, left.x), hints.min.x);
+              let child_x = if edge == DockEdge::Left {start.x} else {end.x - width};
+              child.calc_pos_size(Vec2(child_x, start.y), Vec2(width, left.y), clip, cx);
+              if edge == DockEdge::Left {start.x += width} else {end.x -= width}
+            }
+            DockEdge::Fill => (),
+          }
+        }
+        for &(ref child, edge) in children.iter() {
+          if edge == DockEdge::Fill {
+            child.calc_pos_size(start, (end - start).component_max(Vec2::zero()), clip, cx);
+          }
+        }
+      }
+      Cached_(ref cache, ref child, dirty) => {
+        // If nothing's changed, the positions from last time are still in the maps
+        let rect = Some((pos, size, clip, cx.state.direction));
+        if dirty || cache.rect.get() != rect {
+          child.calc_pos_size(pos, size, clip, cx);
+          cache.place(rect);
         }
       }
-      OverlapPanel_(ref children, ref min_size) => {
-        for child in children.iter() {
-          child.calc_pos_size(pos, size, widget_poses, widget_sizes);
+      Tree_(ref child, _) => {
+        // A node that hasn't changed and is placed where it was last time is already arranged
+        let placed = match *child.borrow() {
+          TreeChild::Unmeasured(ref node) => node.cache.rect.get() == Some((pos, size, clip, cx.state.direction)),
+          _ => false,
+        };
+        if !placed {
+          measure_tree(child, cx);
+          match *child.borrow() {
+            TreeChild::Measured(ref child) => child.calc_pos_size(pos, size, clip, cx),
+            _ => (),
+          }
         }
       }
+      Directed_(direction, ref child) => {
+        let outer = cx.state.direction;
+        cx.state.direction = direction;
+        child.calc_pos_size(pos, size, clip, cx);
+        cx.state.direction = outer;
+      }
     }
   }
 
   fn draw(&mut self, widget_sizes: &HashMap<Id, Vec2<i32>>, widget_poses: &HashMap<Id, Vec2<i32>>, window: &mut GUIWindow) {
     match *self {
-      LWidget_(ref mut widget, _) => {
-        let pos = *widget_poses.get(&widget.id()).unwrap();
-        let size = *widget_sizes.get(&widget.id()).unwrap();
-        widget.draw(pos, size, window);
-      }
-      VPanel_(_, ref mut children, _) => for &mut (ref mut layout,_) in children.iter_mut() {
+      LWidget_(ref mut widget, _) => draw_widget(&mut **widget, widget_sizes, widget_poses, window),
+      VPanel_(_, _, ref mut children, _) => for &mut (ref mut layout,_) in children.iter_mut() {
+        layout.draw(widget_sizes, widget_poses, window);
+      },
+      HPanel_(_, _, ref mut children, _) => for &mut (ref mut layout,_) in children.iter_mut() {
         layout.draw(widget_sizes, widget_poses, window);
       },
-      HPanel_(_, ref mut children, _) => for &mut (ref mut layout,_) in children.iter_mut() {
+      FlowPanel_(_, _, _, ref mut children, _) => for layout in children.iter_mut() {
         layout.draw(widget_sizes, widget_poses, window);
       },
-      OverlapPanel_(ref mut children, _) => for &mut ref mut layout in children.iter_mut() {
+      OverlapPanel_(ref mut children, _) => for &mut (ref mut layout,_) in children.iter_mut() {
         layout.draw(widget_sizes, widget_poses, window);
       },
+      Grid_(_, _, ref mut cells, _) => for cell in cells.iter_mut() {
+        cell.layout.draw(widget_sizes, widget_poses, window);
+      },
+      ScrollPanel_(ref mut state, ref mut child, _) => {
+        let pos = *widget_poses.get(&state.id()).unwrap();
+        let size = *widget_sizes.get(&state.id()).unwrap();
+        window.push_clip(Rect(pos, pos + state.viewport(size)));
+        child.draw(widget_sizes, widget_poses, window);
+        window.pop_clip();
+        state.draw(pos, size, window);
+      }
+      Cached_(_, ref mut child, _) | Directed_(_, ref mut child) => child.draw(widget_sizes, widget_poses, window),
+      Tree_(ref child, _) => match *child.borrow_mut() {
+        TreeChild::Unmeasured(ref mut node) => node.draw(widget_sizes, widget_poses, window),
+        TreeChild::Measured(ref mut child) => child.draw(widget_sizes, widget_poses, window),
+        TreeChild::Measuring => (),
+      },
+      Stack_(index, ref mut children, _) => match children.get_mut(index) {
+        Some(child) => child.draw(widget_sizes, widget_poses, window),
+        None => (),
+      },
+      Dock_(ref mut children, _) => for &mut (ref mut layout,_) in children.iter_mut() {
+        layout.draw(widget_sizes, widget_poses, window);
+      },
+      Splitter_(ref mut state, ref mut children, _) => {
+        for child in children.iter_mut() {
+          child.draw(widget_sizes, widget_poses, window);
+        }
+        let pos = *widget_poses.get(&state.id()).unwrap();
+        let size = *widget_sizes.get(&state.id()).unwrap();
+        state.draw(pos, size, window);
+      }
     }
   }
 }
@@ -251,6 +2323,31 @@ impl<'a> LayoutMinSize<'a> {
 
 
 
+// A widget, then each container it's in
+fn ancestor_path(id: Id, parents: &HashMap<Id, Id>) -> Vec<Id> {
+  let mut path = vec![id];
+  loop {
+    match parents.get(path.last().unwrap()) {
+      Some(&parent) => path.push(parent),
+      None => break,
+    }
+  }
+  path
+}
+
+// The number of framebuffer pixels per screen coordinate. This is None while the window is
+// minimized, since its sizes are 0.
+fn window_content_scale(window: &Window) -> Option<f32> {
+  let (width, _) = window.get_size();
+  let (fb_width, _) = window.get_framebuffer_size();
+  if width <= 0 || fb_width <= 0 {None} else {Some(fb_width as f32 / width as f32)}
+}
+
+// Divides a size by a scale; this converts pixels to logical units
+fn logical_size(pixels: Vec2<i32>, scale: f32) -> Vec2<i32> {
+  Vec2((pixels.x as f32 / scale) as i32, (pixels.y as f32 / scale) as i32)
+}
+
 // TODO: get rid of lifetime by switching to String?
 pub enum GUIWindowMode<'a> {
   Fullscreen,
@@ -320,16 +2417,211 @@ impl<'a> NewGLProgram for UnlitProgram {
 
 
 
+/// Clicks closer together than this, in seconds, count as a double or triple click
+pub const MULTI_CLICK_TIME: f64 = 0.5;
+/// How far the cursor can move between the clicks of a double click
+pub const MULTI_CLICK_DISTANCE: i32 = 4;
+/// How long a button has to be held without moving for a long press, in seconds
+pub const LONG_PRESS_TIME: f64 = 0.5;
+/// How far the cursor has to move with a button held before it's a drag instead of a click
+pub const DRAG_DISTANCE: i32 = 4;
+/// How long the next key of a shortcut sequence can take, in seconds, before the sequence is
+/// abandoned
+pub const SHORTCUT_SEQUENCE_TIME: f64 = 1.5;
+
+/// A key pressed with some modifiers, like Ctrl+S
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct KeyChord {
+  pub key: glfw::Key,
+  pub mods: glfw::Modifiers,
+}
+
+impl KeyChord {
+  pub fn new(key: glfw::Key, mods: glfw::Modifiers) -> KeyChord {
+    KeyChord{key: key, mods: mods}
+  }
+}
+
+/// When a shortcut is checked, relative to sending the key to the focused widget
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ShortcutPhase {
+  /// Before the focused widget gets the key, so it can't be overridden except by a scoped
+  /// shortcut
+  BeforeFocused,
+  /// Only if the focused widget and its containers don't stop the key
+  AfterFocused,
+}
+
+struct Shortcut {
+  chords: Vec<KeyChord>,
+  action: String,
+  phase: ShortcutPhase,
+  // The widget that has to contain the focus for this to work
+  scope: Option<Id>,
+}
+
+// What the chords pressed so far match
+enum ShortcutMatch {
+  // A whole shortcut, with its action
+  Action(ShortcutPhase, String),
+  // The start of a longer shortcut
+  Prefix(ShortcutPhase),
+}
+
+impl ShortcutMatch {
+  fn phase(&self) -> ShortcutPhase {
+    match *self {
+      ShortcutMatch::Action(phase, _) | ShortcutMatch::Prefix(phase) => phase,
+    }
+  }
+}
+
+// Keys that are only pressed along with others, so they don't break up shortcut sequences
+fn is_modifier_key(key: glfw::Key) -> bool {
+  match key {
+    glfw::Key::LeftShift | glfw::Key::RightShift | glfw::Key::LeftControl | glfw::Key::RightControl |
+    glfw::Key::LeftAlt | glfw::Key::RightAlt | glfw::Key::LeftSuper | glfw::Key::RightSuper => true,
+    _ => false,
+  }
+}
+
+// A mouse button that's being held down
+#[derive(Copy, Clone)]
+struct Gesture {
+  button: MouseButton,
+  start_pos: Vec2<i32>,
+  start_time: f64,
+  dragging: bool,
+  // False once it's been long pressed or its click has been cancelled
+  clickable: bool,
+}
+
+/// Turns raw mouse events into clicks, drags and long presses. `GUIWindow` uses one to send
+/// these to widgets, but it only looks at the events and when they happened.
+pub struct GestureRecognizer {
+  // The mouse button that's held down
+  gesture: Option<Gesture>,
+  // The button, position, time and click count of the last click, for counting double clicks
+  last_click: Option<(MouseButton, Vec2<i32>, f64, u32)>,
+}
+
+impl GestureRecognizer {
+  pub fn new() -> GestureRecognizer {
+    GestureRecognizer{gesture: None, last_click: None}
+  }
+
+  /// The button whose gesture is being recognized, if one's held down
+  pub fn held_button(&self) -> Option<MouseButton> {
+    self.gesture.map(|gesture| gesture.button)
+  }
+
+  /// Returns the gesture events made by an event that happened at `time`, in seconds
+  pub fn event(&mut self, event: &Event, time: f64) -> Vec<Event> {
+    let mut events = vec![];
+    match *event {
+      Event::MouseButton(button, Action::Press, _, pos) => if self.gesture.is_none() {
+        self.gesture = Some(Gesture{button: button, start_pos: pos, start_time: time, dragging: false, clickable: true});
+      },
+      Event::MouseMove(pos, _) => match self.gesture {
+        Some(ref mut gesture) if !gesture.dragging => {
+          let moved = pos - gesture.start_pos;
+          if moved.x.abs() > DRAG_DISTANCE || moved.y.abs() > DRAG_DISTANCE {
+            gesture.dragging = true;
+            events.push(Event::DragStart(gesture.button, gesture.start_pos));
+            events.push(Event::Drag(gesture.button, pos));
+          }
+        }
+        Some(ref gesture) => events.push(Event::Drag(gesture.button, pos)),
+        None => (),
+      },
+      Event::MouseButton(button, Action::Release, _, pos) => {
+        let gesture = match self.gesture {
+          Some(gesture) if gesture.button == button => gesture,
+          _ => return events,
+        };
+        self.gesture = None;
+        if gesture.dragging {
+          events.push(Event::DragEnd(button, pos));
+        } else if gesture.clickable {
+          let count = match self.last_click {
+            Some((last_button, last_pos, last_time, last_count)) if last_button == button &&
+                time - last_time <= MULTI_CLICK_TIME &&
+                (pos - last_pos).x.abs() <= MULTI_CLICK_DISTANCE && (pos - last_pos).y.abs() <= MULTI_CLICK_DISTANCE =>
+              last_count + 1,
+            _ => 1,
+          };
+          self.last_click = Some((button, pos, time, count));
+          events.push(Event::Click(button, count, pos));
+          match count {
+            2 => events.push(Event::DoubleClick(button, pos)),
+            3 => events.push(Event::TripleClick(button, pos)),
+            _ => (),
+          }
+        }
+      }
+      _ => (),
+    }
+    events
+  }
+
+  /// Returns a LongPress if the held button has been down without moving for
+  /// `LONG_PRESS_TIME` by `now`. It's only returned once, and the button won't make a click.
+  pub fn check_long_press(&mut self, now: f64) -> Vec<Event> {
+    match self.gesture {
+      Some(ref mut gesture) if !gesture.dragging && gesture.clickable && now - gesture.start_time >= LONG_PRESS_TIME => {
+        gesture.clickable = false;
+        vec![Event::LongPress(gesture.button, gesture.start_pos)]
+      }
+      _ => vec![],
+    }
+  }
+
+  /// Stops the held button from making a click when it's released, such as when it's released
+  /// outside the widget it was pressed on
+  pub fn cancel_click(&mut self) {
+    match self.gesture {
+      Some(ref mut gesture) => gesture.clickable = false,
+      None => (),
+    }
+  }
+
+  /// Forgets the held button and the last click
+  pub fn reset(&mut self) {
+    self.gesture = None;
+    self.last_click = None;
+  }
+}
+
+// A recording being played back, and the overrides the window had before it started
+struct Replay {
+  recording: Recording,
+  scale_override: Option<f32>,
+  size_override: Option<Vec2<i32>>,
+}
+
 pub struct GUIWindow<'a> {
   pub id: Id,
   mode: GUIWindowMode<'a>,
   pub glfw_window: Window,
   glfw_events: Receiver<(f64, glfw::WindowEvent)>,
   events: Vec<Event>,
+  // Keyboard events that weren't handled by the focused widget or its containers
+  unhandled_events: Vec<Event>,
+  /// The size of the window in logical units, which layouts and events use
   pub window_size: Vec2<i32>,
-  // Used in get_events
-  widget_poses: HashMap<Id, Vec2<i32>>,
-  widget_sizes: HashMap<Id, Vec2<i32>>,
+  // The number of pixels per logical unit
+  content_scale: f32,
+  // Overrides the content scale the window reports
+  scale_override: Option<f32>,
+  // Overrides the logical size the window is laid out at
+  size_override: Option<Vec2<i32>>,
+  // The fonts this window can draw with, which may be shared with other windows
+  font_registry: FontRegistry,
+  // This window's copies of the fonts in the registry it's used, which are rendered again
+  // when the scale changes
+  fonts: RefCell<HashMap<FontKey, Font>>,
+  // Where widgets were placed last frame; used in get_events
+  layout_state: LayoutState,
   // Programs and other stuff specific to this window
   // TODO: these shouldn't be public - add a better API
 
@@ -343,9 +2635,45 @@ pub struct GUIWindow<'a> {
   pub untextured_proj_matrix_uni: Mat4Uniform,
   pub untextured_color_uni: ColorUniform,
   font_loader: FontLoader,
-  // TODO: get rid of this hack
-  pub focused: Option<Id>,
-  pub focusable: Vec<Id>,
+  // Used by fill_rect
+  rect_mesh: Mesh,
+  // The rectangles passed to push_clip, each intersected with the ones before it
+  clip_stack: Vec<Rect<i32>>,
+  // Set by stop_propagation while an event is being dispatched
+  propagation_stopped: bool,
+  // The widget that gets all mouse events
+  pointer_capture: Option<Id>,
+  // The last place the cursor was seen in the window, as of the event being dispatched
+  cursor_pos: Option<Vec2<i32>>,
+  // What's held down as of the last event read
+  input: InputState,
+  // Where each frame's events are saved, if they're being recorded
+  recorder: Option<Recorder>,
+  // The recording whose events are used instead of GLFW's, if one is playing
+  replay: Option<Replay>,
+  // The topmost widget under the cursor, then each container it's in
+  hovered: Vec<Id>,
+  // Recognizes clicks and drags
+  gestures: GestureRecognizer,
+  // The widget the held button was pressed on, which gets its gesture events
+  gesture_target: Option<Id>,
+  shortcuts: Vec<Shortcut>,
+  // The first chords of a shortcut sequence that's been started, and when the last one was
+  // pressed
+  pending_chords: Vec<KeyChord>,
+  pending_time: f64,
+  // Set when a key press goes to a shortcut, so the character it types isn't sent
+  suppress_char: bool,
+  // Keys whose presses went to shortcuts, so their repeats and releases don't reach widgets
+  shortcut_keys: Vec<glfw::Key>,
+  // The widget keyboard events go to
+  focused: Option<Id>,
+  // Set by focus; this is applied between events so focus events can be sent
+  pending_focus: Option<Option<Id>>,
+  // The focusable widgets, in the order Tab moves through them
+  focus_order: Vec<Id>,
+  /// The direction layouts in this window run in, unless a `Directed` layout overrides it
+  pub direction: Direction,
 }
 
 
@@ -364,12 +2692,20 @@ impl<'a> GUIWindow<'a> {
       GUIWindowMode::FixedWindowed{ref title, ref size} => create_windowed(glfw, size.x as u32, size.y as u32, title.as_slice()),
     };
 
-    let current_window_size = get_window_size(&window);
+    let content_scale = window_content_scale(&window).unwrap_or(1.0);
+    let current_window_size = logical_size(get_window_size(&window), content_scale);
 
     window.set_key_polling(true);
     window.set_char_polling(true);
     window.set_mouse_button_polling(true);
     window.set_cursor_pos_polling(true);
+    window.set_cursor_enter_polling(true);
+    window.set_scroll_polling(true);
+    window.set_close_polling(true);
+    window.set_focus_polling(true);
+    window.set_iconify_polling(true);
+    window.set_framebuffer_size_polling(true);
+    window.set_drag_and_drop_polling(true);
     glfw.set_swap_interval(0);
 
     Blend.enable();
@@ -399,46 +2735,254 @@ impl<'a> GUIWindow<'a> {
 
     let unlit_program = UnlitProgram::new();
 
-    let untextured_model_view_matrix_uni = Mat4Uniform::new("modelViewMatrix", untextured_program.clone());
-    let untextured_proj_matrix_uni = Mat4Uniform::new("projMatrix", untextured_program.clone());
-    let untextured_color_uni = ColorUniform::new("color", untextured_program.clone());
+    let untextured_model_view_matrix_uni = Mat4Uniform::new("modelViewMatrix", untextured_program.clone());
+    let untextured_proj_matrix_uni = Mat4Uniform::new("projMatrix", untextured_program.clone());
+    let untextured_color_uni = ColorUniform::new("color", untextured_program.clone());
+
+    let font_loader = FontLoader::new();
+    let rect_mesh = Mesh::new(untextured_program.clone(), Primitive::Triangles, MeshUsage::StreamDraw);
+
+    let gui_window = GUIWindow {id: next_id(), mode: mode, glfw_window: window, glfw_events: events, events: Vec::new(), unhandled_events: Vec::new(),
+      window_size: current_window_size, content_scale: content_scale, scale_override: None, size_override: None,
+      font_registry: FontRegistry::new(), fonts: RefCell::new(HashMap::new()),
+      layout_state: LayoutState::new(),
+      unlit_program: unlit_program, untextured_program: untextured_program,
+      text_program: text_program, text_program_2: text_program_2,
+      untextured_model_view_matrix_uni: untextured_model_view_matrix_uni,
+      untextured_proj_matrix_uni: untextured_proj_matrix_uni,
+      untextured_color_uni: untextured_color_uni,
+      font_loader: font_loader,
+      rect_mesh: rect_mesh,
+      clip_stack: vec![],
+      propagation_stopped: false,
+      pointer_capture: None,
+      cursor_pos: None,
+      input: InputState::new(),
+      recorder: None,
+      replay: None,
+      hovered: vec![],
+      gestures: GestureRecognizer::new(),
+      gesture_target: None,
+      shortcuts: vec![],
+      pending_chords: vec![],
+      pending_time: 0.0,
+      suppress_char: false,
+      shortcut_keys: vec![],
+      focused: None,
+      pending_focus: None,
+      focus_order: vec![],
+      direction: Direction::LeftToRight,
+    };
+    gui_window
+  }
+
+  /// The widget keyboard events go to. A change made with `focus` shows up here once the
+  /// widgets have been sent FocusOut and FocusIn.
+  pub fn focused(&self) -> Option<Id> {
+    self.focused
+  }
+
+  /// Focuses a widget, or clears the focus if `None`. The widgets get FocusOut and FocusIn
+  /// once the event being handled is done, or at the start of the next frame. A widget that
+  /// isn't in the layout by then can't be focused, and the focus stays where it was.
+  pub fn focus(&mut self, id: Option<Id>) {
+    self.pending_focus = Some(id);
+  }
+
+  /// Moves the focus to the next focusable widget in the tab order, wrapping around
+  pub fn focus_next(&mut self) {
+    self.move_focus(true);
+  }
+
+  /// Moves the focus to the previous focusable widget in the tab order, wrapping around
+  pub fn focus_prev(&mut self) {
+    self.move_focus(false);
+  }
+
+  fn move_focus(&mut self, forwards: bool) {
+    let len = self.focus_order.len();
+    if len == 0 {return}
+    // Moving twice before the focus is applied moves on from the first move
+    let current = self.pending_focus.unwrap_or(self.focused);
+    let next = match self.focus_order.iter().position(|&id| Some(id) == current) {
+      Some(i) => if forwards {(i + 1) % len} else {(i + len - 1) % len},
+      None => if forwards {0} else {len - 1},
+    };
+    let id = self.focus_order[next];
+    self.focus(Some(id));
+  }
+
+  // Applies the focus set by focus(), sending FocusOut to the widget that had it and FocusIn
+  // to the one that gets it
+  fn apply_focus<'w>(&mut self, widgets: &mut [&'w mut (Widget + 'w)]) {
+    loop {
+      let new_focus = match self.pending_focus.take() {
+        Some(focus) => focus,
+        None => return,
+      };
+      // A widget that isn't in the layout can't take the focus
+      match new_focus {
+        Some(id) => if !widgets.iter().any(|widget| widget.id() == id) {continue},
+        None => (),
+      }
+      if new_focus == self.focused {continue}
+      // A sequence started for the old focus's shortcuts doesn't carry over
+      self.pending_chords.clear();
+      let old_focus = mem::replace(&mut self.focused, new_focus);
+      match old_focus.and_then(|id| widgets.iter_mut().find(|widget| widget.id() == id)) {
+        Some(widget) => widget.handle_event(Event::FocusOut, self),
+        None => (),
+      }
+      match new_focus.and_then(|id| widgets.iter_mut().find(|widget| widget.id() == id)) {
+        Some(widget) => widget.handle_event(Event::FocusIn, self),
+        None => (),
+      }
+    }
+  }
+
+  /// Loads a font whose size is in logical units, and returns the key widgets use for it.
+  /// It's rendered at the window's content scale, and rendered again if that changes.
+  pub fn load_font(&mut self, path: &Path, size: i32) -> FontKey {
+    self.glfw_window.make_current(); // is this needed?
+
+    let key = self.font_registry.register(path, size);
+    self.font(key);
+    key
+  }
+
+  /// Frees a font loaded with `load_font`, so it's no longer kept or rendered again when the
+  /// content scale changes. Its key can't be drawn with afterwards, in this window or any other
+  /// that shares its fonts.
+  pub fn unload_font(&mut self, key: FontKey) {
+    self.font_registry.unregister(key);
+    self.fonts.borrow_mut().remove(&key);
+  }
+
+  /// The fonts this window can draw with
+  pub fn font_registry(&self) -> FontRegistry {
+    self.font_registry.clone()
+  }
+
+  /// Makes this window use another window's fonts, so keys loaded in either one can be used in
+  /// both. Fonts already loaded in this window are added to the registry.
+  pub fn share_fonts(&mut self, registry: FontRegistry) {
+    registry.merge(&self.font_registry);
+    self.font_registry = registry;
+  }
+
+  /// The font with the given key, loaded in this window or one that shares its fonts. Panics
+  /// if the key was never loaded or has been unloaded.
+  pub fn font(&self, key: FontKey) -> Font {
+    match self.fonts.borrow().get(&key) {
+      Some(font) => return font.clone(),
+      None => (),
+    }
+    // Each window has its own OpenGL context, so it renders its own copy of the font
+    let path = match self.font_registry.path(key) {
+      Some(path) => path,
+      None => panic!("Font {:?} wasn't loaded or has been unloaded", key),
+    };
+    let font = Font::with_key(key, &self.font_loader, &path, self.content_scale, self.text_program_2.clone(), self.text_program.clone());
+    self.fonts.borrow_mut().insert(key, font.clone());
+    font
+  }
+
+  /// The number of pixels per logical unit. By default, this is the ratio of the window's
+  /// framebuffer size to its size in screen coordinates, which is 2 on most high DPI displays.
+  pub fn content_scale(&self) -> f32 {
+    self.content_scale
+  }
+
+  /// Makes the window use a particular content scale instead of the one reported by the
+  /// system, or goes back to the system's if `None`
+  pub fn set_content_scale(&mut self, scale: Option<f32>) {
+    self.scale_override = scale;
+  }
+
+  /// Lays the window out as if it were a particular size in logical units, instead of the
+  /// size it really is, or goes back to its real size if `None`
+  pub fn set_layout_size(&mut self, size: Option<Vec2<i32>>) {
+    self.size_override = size;
+  }
+
+  // Checks whether the content scale has changed, such as when the window is moved to a
+  // monitor with a different DPI, and if so renders the fonts again and measures everything
+  fn update_content_scale(&mut self) {
+    // Fonts unloaded by windows this one shares its fonts with aren't rendered again either
+    let unloaded: Vec<FontKey> = self.fonts.borrow().keys().map(|&key| key)
+      .filter(|&key| !self.font_registry.contains(key)).collect();
+    for key in unloaded.iter() {
+      self.fonts.borrow_mut().remove(key);
+    }
+    // A minimized window keeps the scale it had, so its fonts aren't rendered again
+    let scale = match self.scale_override.or(window_content_scale(&self.glfw_window)) {
+      Some(scale) => scale,
+      None => return,
+    };
+    if scale != self.content_scale {
+      self.content_scale = scale;
+      for font in self.fonts.borrow().values() {
+        font.set_scale(scale);
+      }
+      self.layout_state.invalidate();
+    }
+  }
+
+  // The number of logical units per screen coordinate, which cursor positions are in
+  fn logical_per_screen_unit(&self) -> f32 {
+    window_content_scale(&self.glfw_window).unwrap_or(self.content_scale) / self.content_scale
+  }
 
-    let font_loader = FontLoader::new();
+  /// Fills a rectangle with a solid color
+  pub fn fill_rect(&mut self, pos: Vec2<i32>, size: Vec2<i32>, color: Color<f32>) {
+    let start: Vec2<f32> = pos.cvt();
+    let end: Vec2<f32> = (pos + size).cvt();
+    self.rect_mesh.clear();
+    add_vertex_untextured(&mut self.rect_mesh, start, color);
+    add_vertex_untextured(&mut self.rect_mesh, Vec2(end.x, start.y), color);
+    add_vertex_untextured(&mut self.rect_mesh, end, color);
+    add_vertex_untextured(&mut self.rect_mesh, Vec2(start.x, end.y), color);
+    self.rect_mesh.triangle(0, 2, 1);
+    self.rect_mesh.triangle(2, 0, 3);
+    self.untextured_model_view_matrix_uni.set(Mat4::id());
+    self.untextured_proj_matrix_uni.set(Mat4::ortho_flip(self.window_size.x as f32, self.window_size.y as f32));
+    self.untextured_color_uni.set(Color::white());
+    self.rect_mesh.draw();
+  }
 
-    let gui_window = GUIWindow {id: next_id(), mode: mode, glfw_window: window, glfw_events: events, events: Vec::new(),
-      window_size: current_window_size,
-      widget_poses: HashMap::new(), widget_sizes: HashMap::new(),
-      unlit_program: unlit_program, untextured_program: untextured_program,
-      text_program: text_program, text_program_2: text_program_2,
-      untextured_model_view_matrix_uni: untextured_model_view_matrix_uni,
-      untextured_proj_matrix_uni: untextured_proj_matrix_uni,
-      untextured_color_uni: untextured_color_uni,
-      font_loader: font_loader,
-      focused: None,
-      focusable: vec![],
+  /// Restricts drawing to a rectangle, and to any rectangles it's nested in, until the
+  /// matching call to pop_clip
+  pub fn push_clip(&mut self, rect: Rect<i32>) {
+    let rect = match self.clip_stack.last() {
+      Some(&outer) => intersect_rects(outer, rect),
+      None => rect,
     };
-    gui_window
+    self.clip_stack.push(rect);
+    self.apply_clip();
   }
 
-  pub fn focus_next(&mut self) {
-    //biggest hack I've ever made - just a prototype
-    if self.focused.is_none() {
-      self.focused = Some(self.focusable[0]);
-    } else {
-      if self.focused == Some(self.focusable[0]) {
-        self.focused = Some(self.focusable[1]);
-      } else if self.focused == Some(self.focusable[1]) {
-        self.focused = Some(self.focusable[2]);
-      } else if self.focused == Some(self.focusable[2]) {
-        self.focused = Some(self.focusable[0]);
+  pub fn pop_clip(&mut self) {
+    self.clip_stack.pop();
+    self.apply_clip();
+  }
+
+  fn apply_clip(&self) {
+    match self.clip_stack.last() {
+      Some(rect) => {
+        ScissorTest.enable();
+        let size = rect.end - rect.start;
+        // OpenGL measures from the bottom of the window, in pixels
+        let scale = self.content_scale;
+        let pixels = |x: i32| (x as f32 * scale).round() as i32;
+        unsafe {gl::Scissor(pixels(rect.start.x), pixels(self.window_size.y - rect.end.y), pixels(size.x), pixels(size.y));}
       }
+      None => ScissorTest.disable(),
     }
   }
 
-  pub fn load_font(&mut self, path: &Path, size: i32) -> Font {
-    self.glfw_window.make_current(); // is this needed?
-
-    Font::new(&self.font_loader, path, size, self.text_program_2.clone(), self.text_program.clone())
+  /// Like draw_gui, but for a tree that's kept between frames
+  pub fn draw_tree(&mut self, root: &mut Node, glfw: &mut Glfw, background_color: Color<f32>) {
+    self.draw_gui(root.as_layout(), glfw, background_color);
   }
 
   pub fn draw_gui(&mut self, layout: Layout, glfw: &mut Glfw, background_color: Color<f32>) {
@@ -449,6 +2993,21 @@ impl<'a> GUIWindow<'a> {
   pub fn draw_gui_with_extra<F: FnMut(&mut GUIWindow)>(&mut self, layout: Layout, glfw: &mut Glfw, background_color: Color<f32>, mut extra_drawing: F) {
     self.glfw_window.make_current();
 
+    // A recorded frame is laid out at the size and scale it was recorded at, so its events
+    // hit the same widgets
+    let replay_frame = match self.replay {
+      Some(ref mut replay) => replay.recording.next_frame(),
+      None => None,
+    };
+    match replay_frame {
+      Some(ref frame) => {
+        self.scale_override = Some(frame.content_scale);
+        self.size_override = Some(frame.window_size);
+      }
+      None => self.finish_replay(),
+    }
+    self.update_content_scale();
+
     check_gl_error("draw_gui");
 
     unsafe {gl::ClearColor(background_color.r, background_color.g, background_color.b, 1.0)};
@@ -460,27 +3019,44 @@ impl<'a> GUIWindow<'a> {
     /*self.unlit_proj_matrix_uni.set(Mat4::ortho_flip(window_size.x as f32, window_size.y as f32));
     self.unlit_model_view_matrix_uni.set(Mat4::id());*/
 
-    let mut layout = layout.into_layout_min_size(self);
-    let min_size = layout.min_size();
-    let current_window_size = get_window_size(&self.glfw_window);
-    let min_window_size = match self.mode {
-      GUIWindowMode::Fullscreen => current_window_size,
-      GUIWindowMode::FixedWindowed{ref title, ref size} => *size,
-      GUIWindowMode::Windowed{ref title, min_size: ref window_min_size} => min_size.component_max(*window_min_size),
+    let current_window_size = match self.size_override {
+      Some(size) => size,
+      None => logical_size(get_window_size(&self.glfw_window), self.content_scale),
+    };
+    let (fixed_size, window_min_size) = match self.mode {
+      GUIWindowMode::Fullscreen => (Some(current_window_size), Vec2::zero()),
+      GUIWindowMode::FixedWindowed{ref title, ref size} => (Some(*size), Vec2::zero()),
+      GUIWindowMode::Windowed{ref title, ref min_size} => (None, *min_size),
     };
 
-    let real_size = current_window_size.component_max(min_window_size);
-
-    let mut widget_sizes = HashMap::new();
-    let mut widget_poses = HashMap::new();
+    // This is kept from the last frame, since Cached layouts that haven't changed don't
+    // update it
+    let mut layout_state = mem::replace(&mut self.layout_state, LayoutState::new());
+    layout_state.direction = self.direction;
+    layout_state.begin_pass();
+    let (mut layout, real_size) = {
+      let mut cx = LayoutContext{measure: self, state: &mut layout_state};
+      let layout = layout.into_layout_min_size(&mut cx);
+      // How tall the layout needs to be depends on how wide the window is, since text may wrap
+      let mut min_size = layout.min_size();
+      min_size.y = layout.height_for_width(cmp::max(current_window_size.x, min_size.x), &mut cx);
+      let min_window_size = match fixed_size {
+        Some(size) => size,
+        None => min_size.component_max(window_min_size),
+      };
 
-    layout.calc_pos_size(Vec2::zero(), real_size, &mut widget_poses, &mut widget_sizes);
+      let real_size = current_window_size.component_max(min_window_size);
+      layout.calc_pos_size(Vec2::zero(), real_size, Rect(Vec2::zero(), real_size), &mut cx);
+      (layout, real_size)
+    };
 
     let desired_window_size = if self.mode.fixed_size() {current_window_size} else {real_size};
     let new_window_size = current_window_size.component_max(desired_window_size);
     self.window_size = new_window_size;
     if new_window_size != current_window_size {
-      self.glfw_window.set_size(new_window_size.x, new_window_size.y);
+      // The window's size is set in screen coordinates
+      let screen_size = logical_size(new_window_size, self.logical_per_screen_unit());
+      self.glfw_window.set_size(screen_size.x, screen_size.y);
       // TODO: move window to middle of screen?
     }
     if !self.glfw_window.is_visible() {
@@ -490,59 +3066,524 @@ impl<'a> GUIWindow<'a> {
     // TODO: do we really need SIX different variables for window size?
     // println!("{} {} {} {} {} {}", min_size, current_window_size, min_window_size, real_size, desired_window_size, new_window_size);
 
-    unsafe {gl::Viewport(0, 0, new_window_size.x, new_window_size.y);}
+    // Everything's drawn in logical units, and the viewport scales it up to pixels
+    let framebuffer_size = get_window_size(&self.glfw_window);
+    unsafe {gl::Viewport(0, 0, framebuffer_size.x, framebuffer_size.y);}
 
     // We draw this before the rest of the GUI so widgets can overlay it
     extra_drawing(self);
 
-    layout.draw(&widget_sizes, &widget_poses, self);
+    layout.draw(&layout_state.widget_sizes, &layout_state.widget_poses, self);
 
 
     self.glfw_window.swap_buffers();
 
 
     let mut all_widgets = Vec::new();
-    layout.collect_widgets(&mut all_widgets);
+    let mut parents = HashMap::new();
+    layout.collect_widgets(&mut all_widgets, &mut parents, None);
+
+    layout_state.forget_missing(all_widgets.as_slice());
 
 
     self.events = Vec::new();
+    self.unhandled_events = Vec::new();
     glfw.poll_events();
-    for (_, event) in glfw::flush_messages(&self.glfw_events) {
-      self.events.push(Event::from_glfw(event, &self.glfw_window));
+    let cursor_scale = self.logical_per_screen_unit();
+    let mut event_times = Vec::new();
+    let frame_time = match replay_frame {
+      Some(frame) => {
+        for event in frame.events.iter() {
+          self.input.update(event);
+        }
+        self.events = frame.events;
+        event_times = frame.event_times;
+        // What the user types and clicks is ignored while a recording plays, but what happens
+        // to the window still gets through, so it can be closed. These don't change the input
+        // state, so the recording plays out the same way.
+        for (time, event) in glfw::flush_messages(&self.glfw_events) {
+          match event {
+            glfw::WindowEvent::Close | glfw::WindowEvent::Focus(_) | glfw::WindowEvent::Iconify(_) |
+            glfw::WindowEvent::FramebufferSize(_, _) => {
+              self.events.push(Event::from_glfw(event, &self.glfw_window, cursor_scale, &self.input));
+              event_times.push(time);
+            }
+            _ => (),
+          }
+        }
+        frame.time
+      }
+      None => {
+        for (time, event) in glfw::flush_messages(&self.glfw_events) {
+          let event = Event::from_glfw(event, &self.glfw_window, cursor_scale, &self.input);
+          self.input.update(&event);
+          self.events.push(event);
+          event_times.push(time);
+        }
+        glfw.get_time()
+      }
+    };
+    // A recording that can't be written to is stopped
+    let recorded = match self.recorder {
+      Some(ref mut recorder) =>
+        recorder.record_frame(frame_time, current_window_size, self.content_scale, self.events.as_slice(), event_times.as_slice()).is_ok(),
+      None => true,
+    };
+    if !recorded {
+      self.recorder = None;
+    }
+
+    self.layout_state = layout_state;
+
+    // The widget with the pointer may have been removed
+    match self.pointer_capture {
+      Some(id) => if !all_widgets.iter().any(|widget| widget.id() == id) {
+        self.pointer_capture = None;
+      },
+      None => (),
     }
 
-    self.widget_poses = widget_poses;
-    self.widget_sizes = widget_sizes;
+    // A focused widget that's no longer in the layout loses the focus
+    match self.focused {
+      Some(id) => if !all_widgets.iter().any(|widget| widget.id() == id) {
+        self.focused = None;
+      },
+      None => (),
+    }
+    let mut focusable: Vec<(usize, Option<i32>, Id)> = all_widgets.iter().filter(|widget| widget.focusable()).enumerate()
+      .map(|(i, widget)| (i, widget.tab_index(), widget.id())).collect();
+    // Indexed widgets first, then the rest in layout order
+    focusable.sort_by(|&(i, a, _), &(j, b, _)| match (a, b) {
+      (Some(a), Some(b)) => if a == b {i.cmp(&j)} else {a.cmp(&b)},
+      (Some(_), None) => cmp::Ordering::Less,
+      (None, Some(_)) => cmp::Ordering::Greater,
+      (None, None) => i.cmp(&j),
+    });
+    self.focus_order = focusable.into_iter().map(|(_, _, id)| id).collect();
+    self.apply_focus(all_widgets.as_mut_slice());
+
+    // Widgets may have moved under the cursor
+    let cursor_pos = self.cursor_pos;
+    self.update_hover(cursor_pos, all_widgets.as_mut_slice(), &parents);
+
+    for (event, &time) in self.events.clone().iter().zip(event_times.iter()) {
+      // Only events that move the cursor can change which widget it's over
+      let moved = match *event {
+        Event::MouseLeave => {
+          self.cursor_pos = None;
+          true
+        }
+        _ => match event.position() {
+          Some(pos) => {
+            self.cursor_pos = Some(pos);
+            true
+          }
+          None => false,
+        },
+      };
+      if moved {
+        let cursor_pos = self.cursor_pos;
+        self.update_hover(cursor_pos, all_widgets.as_mut_slice(), &parents);
+      }
+      // Clicking or scrolling abandons a shortcut sequence
+      match *event {
+        Event::MouseButton(_, Action::Press, _, _) | Event::Scroll(_, _) => self.pending_chords.clear(),
+        _ => (),
+      }
 
-    for event in self.events.clone().iter() {
       match event.position() {
-        Some(pos) => for widget in all_widgets.iter_mut() {
-          let widget_pos = *self.widget_poses.get(&widget.id()).unwrap();
-          let widget_size = *self.widget_sizes.get(&widget.id()).unwrap();
-          let widget_aabb = AABB2::from_pos_size(widget_pos, widget_size);
-          if widget_aabb.contains_vec(pos) {
+        Some(pos) => match self.pointer_capture {
+          Some(id) => {
+            let widget = all_widgets.iter_mut().find(|widget| widget.id() == id).unwrap();
+            let widget_pos = *self.layout_state.widget_poses.get(&id).unwrap();
             widget.handle_event(event.for_widget(widget_pos), self);
           }
+          None => self.dispatch_at(event, pos, all_widgets.as_mut_slice(), &parents),
+        },
+        None => if event.is_keyboard() {
+          self.dispatch_key(event, time, all_widgets.as_mut_slice(), &parents);
         },
+      }
+      self.apply_focus(all_widgets.as_mut_slice());
+      self.recognize_gesture(event, time, all_widgets.as_mut_slice(), &parents);
+    }
+    self.check_long_press(frame_time, all_widgets.as_mut_slice(), &parents);
+  }
+
+  // Turns raw mouse events into clicks and drags, which are sent to the widget the button
+  // was pressed on and bubble up from there
+  fn recognize_gesture<'w>(&mut self, event: &Event, time: f64, widgets: &mut [&'w mut (Widget + 'w)], parents: &HashMap<Id, Id>) {
+    match *event {
+      Event::MouseButton(_, Action::Press, _, pos) => if self.gestures.held_button().is_none() {
+        self.gesture_target = self.hit_path(pos, widgets, parents).first().map(|&id| id);
+      },
+      Event::MouseButton(button, Action::Release, _, pos) => if self.gestures.held_button() == Some(button) {
+        // It's only a click if the button's released over the widget it was pressed on
+        let inside = match self.gesture_target {
+          Some(id) => widgets.iter().any(|widget| widget.id() == id) && self.widget_contains(id, pos),
+          None => true,
+        };
+        if !inside {self.gestures.cancel_click()}
+      },
+      _ => (),
+    }
+    let events = self.gestures.event(event, time);
+    self.send_gestures(events, widgets, parents);
+  }
+
+  fn check_long_press<'w>(&mut self, now: f64, widgets: &mut [&'w mut (Widget + 'w)], parents: &HashMap<Id, Id>) {
+    let events = self.gestures.check_long_press(now);
+    self.send_gestures(events, widgets, parents);
+  }
+
+  // Sends the events made by the gesture recognizer; the window gets them too
+  fn send_gestures<'w>(&mut self, events: Vec<Event>, widgets: &mut [&'w mut (Widget + 'w)], parents: &HashMap<Id, Id>) {
+    let target = self.gesture_target;
+    for event in events.into_iter() {
+      let path = match target {
+        Some(id) if widgets.iter().any(|widget| widget.id() == id) => ancestor_path(id, parents),
+        _ => vec![],
+      };
+      self.dispatch_path(&event, path, widgets);
+      self.events.push(event);
+    }
+  }
+
+  /// Sends all mouse events to a widget, wherever the cursor is, until `release_pointer` is
+  /// called. Widgets usually do this when a button is pressed on them, so they can be dragged.
+  pub fn capture_pointer(&mut self, id: Id) {
+    self.pointer_capture = Some(id);
+  }
+
+  pub fn release_pointer(&mut self) {
+    self.pointer_capture = None;
+  }
+
+  /// The widget that has captured the pointer, if any
+  pub fn pointer_capture(&self) -> Option<Id> {
+    self.pointer_capture
+  }
+
+  /// Stops the event that's being handled from going to any more widgets
+  pub fn stop_propagation(&mut self) {
+    self.propagation_stopped = true;
+  }
+
+  // Sends a positional event to the topmost widget under it, after letting the containers
+  // it's in capture it, and then bubbles it up through those containers
+  fn dispatch_at<'w>(&mut self, event: &Event, pos: Vec2<i32>, widgets: &mut [&'w mut (Widget + 'w)], parents: &HashMap<Id, Id>) {
+    let path = self.hit_path(pos, widgets, parents);
+    self.dispatch_path(event, path, widgets);
+  }
+
+  // Sends a keyboard event to the focused widget, bubbling it up through the containers
+  // it's in. If none of them stop it, it's kept for get_unhandled_events.
+  fn dispatch_key<'w>(&mut self, event: &Event, time: f64, widgets: &mut [&'w mut (Widget + 'w)], parents: &HashMap<Id, Id>) {
+    let path = match self.focused {
+      Some(id) if widgets.iter().any(|widget| widget.id() == id) => ancestor_path(id, parents),
+      _ => vec![],
+    };
+    let chord = match *event {
+      Event::Key(key, _, Action::Press, mods) if !is_modifier_key(key) => Some(KeyChord::new(key, mods)),
+      _ => None,
+    };
+    match *event {
+      // GLFW sends the character a key types after the key press, so this is the character of
+      // the last key that was pressed
+      Event::Char(_) if self.suppress_char => {
+        self.suppress_char = false;
+        return;
+      }
+      Event::Key(key, _, Action::Repeat, _) if self.shortcut_keys.contains(&key) => return,
+      Event::Key(key, _, Action::Release, _) if self.shortcut_keys.contains(&key) => {
+        self.shortcut_keys.retain(|&k| k != key);
+        return;
+      }
+      // While a sequence is partly typed, modifiers, repeats and releases are the shortcuts'
+      // too, so widgets don't see keys without their presses
+      Event::Key(_, _, _, _) if chord.is_none() && !self.pending_chords.is_empty() &&
+        time - self.pending_time <= SHORTCUT_SEQUENCE_TIME => return,
+      _ => (),
+    }
+    // The shortcut the key runs or continues, and the chords pressed for it so far
+    let found = match chord {
+      Some(chord) => {
+        self.suppress_char = false;
+        self.shortcut_keys.retain(|&k| k != chord.key);
+        // In the middle of a sequence, the next key goes straight to the shortcuts, unless
+        // it's been too long
+        let mut sequence = mem::replace(&mut self.pending_chords, vec![]);
+        if time - self.pending_time > SHORTCUT_SEQUENCE_TIME {
+          sequence.clear();
+        }
+        self.pending_time = time;
+        let in_sequence = !sequence.is_empty();
+        sequence.push(chord);
+        let continued = self.find_shortcut(sequence.as_slice(), &path);
+        let found = match continued {
+          Some(found) => Some((sequence, found)),
+          // A key that doesn't continue the sequence can start a new one
+          None if in_sequence => self.find_shortcut(&[chord], &path).map(|found| (vec![chord], found)),
+          None => None,
+        };
+        match found {
+          Some((sequence, found)) => {
+            if (in_sequence && sequence.len() > 1) || found.phase() == ShortcutPhase::BeforeFocused {
+              self.run_shortcut(sequence, found, &path, widgets);
+              self.used_by_shortcut(chord.key);
+              return;
+            }
+            Some((sequence, found))
+          }
+          None => None,
+        }
+      }
+      None => None,
+    };
+    if !self.dispatch_path(event, path.clone(), widgets) {
+      match found {
+        Some((sequence, found)) => {
+          self.run_shortcut(sequence, found, &path, widgets);
+          // The widgets saw the press, so they get its release, but not the character
+          self.suppress_char = true;
+          return;
+        }
         None => (),
       }
+      // Tab moves the focus, unless the focused widget used it
+      match *event {
+        Event::Key(glfw::Key::Tab, _, action, mods) if action.is_down() => {
+          if mods.contains(glfw::Shift) {self.focus_prev()} else {self.focus_next()}
+          return;
+        }
+        _ => (),
+      }
+      self.unhandled_events.push(event.clone());
+    }
+  }
+
+  /// Binds a sequence of key chords to a named action. When they're pressed, an
+  /// `Event::Shortcut` with the action is sent to the focused widget and its containers, and
+  /// added to `get_events`. If one shortcut's chords start with another's, the shorter one wins.
+  pub fn add_shortcut(&mut self, chords: &[KeyChord], action: &str, phase: ShortcutPhase) {
+    self.shortcuts.push(Shortcut{chords: chords.to_vec(), action: action.to_string(), phase: phase, scope: None});
+  }
+
+  /// Like `add_shortcut`, but the shortcut only works while the widget `scope` or something
+  /// in it is focused, and it takes priority over shortcuts with the same keys from outer
+  /// scopes, whichever phase they're in. A text field can use this to override a global
+  /// binding.
+  pub fn add_scoped_shortcut(&mut self, scope: Id, chords: &[KeyChord], action: &str, phase: ShortcutPhase) {
+    self.shortcuts.push(Shortcut{chords: chords.to_vec(), action: action.to_string(), phase: phase, scope: Some(scope)});
+  }
+
+  /// Removes every shortcut for an action
+  pub fn remove_shortcut(&mut self, action: &str) {
+    self.shortcuts.retain(|shortcut| shortcut.action.as_slice() != action);
+  }
+
+  // Finds what the chords pressed so far match among the shortcuts whose scope contains the
+  // focus. Whole shortcuts win over longer ones they start. Otherwise the innermost scope wins,
+  // whichever phase its shortcut is in; global shortcuts are outside every scope.
+  fn find_shortcut(&self, sequence: &[KeyChord], focus_path: &Vec<Id>) -> Option<ShortcutMatch> {
+    // The depth and phase of the best match so far, and its action if it's a whole shortcut
+    let mut best: Option<(usize, ShortcutPhase, Option<String>)> = None;
+    for shortcut in self.shortcuts.iter() {
+      let depth = match shortcut.scope {
+        Some(scope) => match focus_path.iter().position(|&id| id == scope) {
+          Some(depth) => depth,
+          None => continue,
+        },
+        None => focus_path.len(),
+      };
+      let whole = shortcut.chords.as_slice() == sequence;
+      if !whole && !(shortcut.chords.len() > sequence.len() && &shortcut.chords[..sequence.len()] == sequence) {continue}
+      let better = match best {
+        None => true,
+        Some((best_depth, best_phase, ref best_action)) => match (whole, best_action.is_some()) {
+          (true, false) => true,
+          (false, true) => false,
+          // On a tie, the shortcut that's checked first wins
+          _ => depth < best_depth || (depth == best_depth && shortcut.phase == ShortcutPhase::BeforeFocused &&
+            best_phase == ShortcutPhase::AfterFocused),
+        },
+      };
+      if better {
+        best = Some((depth, shortcut.phase, if whole {Some(shortcut.action.clone())} else {None}));
+      }
+    }
+    best.map(|(_, phase, action)| match action {
+      Some(action) => ShortcutMatch::Action(phase, action),
+      None => ShortcutMatch::Prefix(phase),
+    })
+  }
+
+  // Keeps the rest of a key that went to a shortcut, the character it types and its repeats
+  // and release, from reaching widgets
+  fn used_by_shortcut(&mut self, key: glfw::Key) {
+    self.suppress_char = true;
+    self.shortcut_keys.push(key);
+  }
+
+  // Sends the shortcut the chords completed, or waits for the next key if they start one
+  fn run_shortcut<'w>(&mut self, sequence: Vec<KeyChord>, found: ShortcutMatch, focus_path: &Vec<Id>, widgets: &mut [&'w mut (Widget + 'w)]) {
+    match found {
+      ShortcutMatch::Action(_, action) => {
+        let event = Event::Shortcut(action);
+        self.dispatch_path(&event, focus_path.clone(), widgets);
+        self.events.push(event);
+      }
+      ShortcutMatch::Prefix(_) => self.pending_chords = sequence,
+    }
+  }
+
+
+  // Lets each container in the path capture an event, outermost first, and then sends it to
+  // each widget in the path, starting with the first. Returns whether propagation was stopped.
+  fn dispatch_path<'w>(&mut self, event: &Event, path: Vec<Id>, widgets: &mut [&'w mut (Widget + 'w)]) -> bool {
+    self.propagation_stopped = false;
+    if path.is_empty() {return false}
+    let indices: HashMap<Id, usize> = widgets.iter().enumerate().map(|(i, widget)| (widget.id(), i)).collect();
+
+    for &id in path[1..].iter().rev() {
+      let widget_pos = *self.layout_state.widget_poses.get(&id).unwrap();
+      widgets[*indices.get(&id).unwrap()].capture_event(event.for_widget(widget_pos), self);
+      if self.propagation_stopped {return true}
+    }
+    for &id in path.iter() {
+      let widget_pos = *self.layout_state.widget_poses.get(&id).unwrap();
+      widgets[*indices.get(&id).unwrap()].handle_event(event.for_widget(widget_pos), self);
+      if self.propagation_stopped {return true}
+    }
+    false
+  }
+
+  // The topmost widget at a point, then each container it's in
+  fn hit_path<'w>(&self, pos: Vec2<i32>, widgets: &[&'w mut (Widget + 'w)], parents: &HashMap<Id, Id>) -> Vec<Id> {
+    match widgets.iter().rposition(|widget| self.widget_contains(widget.id(), pos)) {
+      Some(target) => ancestor_path(widgets[target].id(), parents),
+      None => vec![],
+    }
+  }
+
+  // Moves the hover to the widgets at a point, or to none if the cursor isn't in the window,
+  // sending MouseLeave to the ones it leaves, innermost first, and MouseEnter to the ones it
+  // enters, outermost first
+  fn update_hover<'w>(&mut self, pos: Option<Vec2<i32>>, widgets: &mut [&'w mut (Widget + 'w)], parents: &HashMap<Id, Id>) {
+    let path = match pos {
+      Some(pos) => self.hit_path(pos, widgets, parents),
+      None => vec![],
+    };
+    let old_path = mem::replace(&mut self.hovered, path.clone());
+    for id in old_path.iter() {
+      if !path.contains(id) {
+        // It may have been removed from the layout
+        match widgets.iter_mut().find(|widget| widget.id() == *id) {
+          Some(widget) => widget.handle_event(Event::MouseLeave, self),
+          None => (),
+        }
+      }
+    }
+    for id in path.iter().rev() {
+      if !old_path.contains(id) {
+        let widget = widgets.iter_mut().find(|widget| widget.id() == *id).unwrap();
+        widget.handle_event(Event::MouseEnter, self);
+      }
     }
   }
 
+  /// The topmost widget under the cursor
+  pub fn hovered(&self) -> Option<Id> {
+    self.hovered.first().map(|&id| id)
+  }
+
   pub fn get_events(&self) -> Vec<Event> {
     self.events.clone()
   }
 
+  /// The keys and buttons held down and the cursor position, as of the last event
+  pub fn input(&self) -> &InputState {
+    &self.input
+  }
+
+  /// Starts saving the events this window reads to a file, a frame at a time, replacing any
+  /// recording that's already going. The window forgets the focus, the hover and anything
+  /// held down, so a replay starts from the same state.
+  pub fn start_recording(&mut self, path: &Path) -> IoResult<()> {
+    self.recorder = Some(try!(Recorder::new(path)));
+    self.reset_dispatch();
+    Ok(())
+  }
+
+  pub fn stop_recording(&mut self) {
+    self.recorder = None;
+  }
+
+  /// Plays back a file saved by `start_recording`. Starting with the next frame, each frame
+  /// gets the events of the next recorded frame instead of what the user does, until the
+  /// recording runs out. For it to play out the same way, the app should draw the same
+  /// layouts and start playing at the same point it started recording.
+  /// Fails without changing anything if the file can't be read or isn't a recording.
+  pub fn replay(&mut self, path: &Path) -> Result<(), String> {
+    let recording = try!(Recording::load(path));
+    self.finish_replay();
+    self.replay = Some(Replay{recording: recording, scale_override: self.scale_override, size_override: self.size_override});
+    self.reset_dispatch();
+    Ok(())
+  }
+
+  // Stops playing a recording, if one is playing, and goes back to the overrides from before it
+  fn finish_replay(&mut self) {
+    match self.replay.take() {
+      Some(replay) => {
+        self.scale_override = replay.scale_override;
+        self.size_override = replay.size_override;
+      }
+      None => (),
+    }
+  }
+
+  // Forgets everything about the events read so far, as at the start of a recording
+  fn reset_dispatch(&mut self) {
+    self.input = InputState::new();
+    self.focused = None;
+    self.pending_focus = None;
+    self.hovered.clear();
+    self.cursor_pos = None;
+    self.pointer_capture = None;
+    self.gestures.reset();
+    self.gesture_target = None;
+    self.pending_chords.clear();
+    self.suppress_char = false;
+    self.shortcut_keys.clear();
+  }
+
+  /// Whether a recording is being played back
+  pub fn replaying(&self) -> bool {
+    self.replay.is_some()
+  }
+
+  /// The keyboard events from the last frame that no widget stopped the propagation of
+  pub fn get_unhandled_events(&self) -> Vec<Event> {
+    self.unhandled_events.clone()
+  }
+
+  // Whether a point is inside the visible part of a widget
+  fn widget_contains(&self, id: Id, pos: Vec2<i32>) -> bool {
+    let widget_pos = *self.layout_state.widget_poses.get(&id).unwrap();
+    let widget_size = *self.layout_state.widget_sizes.get(&id).unwrap();
+    let widget_aabb = AABB2::from_pos_size(widget_pos, widget_size);
+    widget_aabb.contains_vec(pos) && rect_contains(*self.layout_state.widget_clips.get(&id).unwrap(), pos)
+  }
+
   /// You usually shouldn't use this directly.
   pub fn get_widget_events(&self, widget: &Widget) -> Vec<Event> {
     let mut events = Vec::new();
     for event in self.events.iter() {
       match event.position() {
         Some(pos) => {
-          let widget_pos = *self.widget_poses.get(&widget.id()).unwrap();
-          let widget_size = *self.widget_sizes.get(&widget.id()).unwrap();
-          let widget_aabb = AABB2::from_pos_size(widget_pos, widget_size);
-          if widget_aabb.contains_vec(pos) {
+          if self.widget_contains(widget.id(), pos) {
+            let widget_pos = *self.layout_state.widget_poses.get(&widget.id()).unwrap();
             events.push(event.for_widget(widget_pos));
           }
         },
@@ -553,66 +3594,225 @@ impl<'a> GUIWindow<'a> {
   }
 }
 
+impl<'a> Measure for GUIWindow<'a> {
+  fn content_scale(&self) -> f32 {self.content_scale}
+  fn string_size(&mut self, font: FontKey, text: &str) -> Vec2<i32> {self.font(font).string_size(text)}
+  fn vert_advance(&mut self, font: FontKey) -> i32 {self.font(font).vert_advance()}
+}
 
-// Repeat is treated the same as Press
-#[derive(Debug, Copy, Clone)]
-pub enum Action {Press, Release}
+// Repeat is sent while a key is held down, after the first Press
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Action {Press, Release, Repeat}
 
 impl Action {
   fn from_glfw(action: glfw::Action) -> Action {
     match action {
-      glfw::Action::Press | glfw::Action::Repeat => Action::Press,
+      glfw::Action::Press => Action::Press,
       glfw::Action::Release => Action::Release,
+      glfw::Action::Repeat => Action::Repeat,
+    }
+  }
+
+  /// Whether this is a Press or a Repeat
+  pub fn is_down(self) -> bool {
+    match self {
+      Action::Press | Action::Repeat => true,
+      Action::Release => false,
     }
   }
 }
 
-// TODO: more events
+/// The keys and mouse buttons that are held down, and where the cursor is. This is updated
+/// from each event as it's read, so it matches the events in `get_events`.
 #[derive(Debug, Clone)]
+pub struct InputState {
+  keys: Vec<glfw::Key>,
+  buttons: Vec<MouseButton>,
+  cursor_pos: Option<Vec2<i32>>,
+}
+
+impl InputState {
+  pub fn new() -> InputState {
+    InputState {keys: vec![], buttons: vec![], cursor_pos: None}
+  }
+
+  pub fn key_down(&self, key: glfw::Key) -> bool {
+    self.keys.iter().any(|&k| k == key)
+  }
+
+  pub fn button_down(&self, button: MouseButton) -> bool {
+    self.buttons.iter().any(|&b| b == button)
+  }
+
+  /// The keys that are held down, in the order they were pressed. Keys GLFW doesn't know
+  /// aren't included.
+  pub fn pressed_keys(&self) -> &[glfw::Key] {
+    self.keys.as_slice()
+  }
+
+  /// The mouse buttons that are held down, in the order they were pressed
+  pub fn pressed_buttons(&self) -> &[MouseButton] {
+    self.buttons.as_slice()
+  }
+
+  /// Where the cursor is in logical units, or None if it's outside the window
+  pub fn cursor_pos(&self) -> Option<Vec2<i32>> {
+    self.cursor_pos
+  }
+
+  /// The modifiers whose keys are held down
+  pub fn modifiers(&self) -> glfw::Modifiers {
+    let mut mods = glfw::Modifiers::empty();
+    if self.key_down(glfw::Key::LeftShift) || self.key_down(glfw::Key::RightShift) {mods.insert(glfw::Shift)}
+    if self.key_down(glfw::Key::LeftControl) || self.key_down(glfw::Key::RightControl) {mods.insert(glfw::Control)}
+    if self.key_down(glfw::Key::LeftAlt) || self.key_down(glfw::Key::RightAlt) {mods.insert(glfw::Alt)}
+    if self.key_down(glfw::Key::LeftSuper) || self.key_down(glfw::Key::RightSuper) {mods.insert(glfw::Super)}
+    mods
+  }
+
+  fn update(&mut self, event: &Event) {
+    match *event {
+      // Unknown keys would all look like the same key, so they aren't tracked
+      Event::Key(glfw::Key::Unknown, _, _, _) => (),
+      // A key that's repeating is down even if its press was missed, such as when it was
+      // pressed before the window got the focus
+      Event::Key(key, _, Action::Press, _) | Event::Key(key, _, Action::Repeat, _) =>
+        if !self.key_down(key) {self.keys.push(key)},
+      Event::Key(key, _, Action::Release, _) => self.keys.retain(|&k| k != key),
+      Event::MouseButton(button, Action::Press, _, pos) => {
+        if !self.button_down(button) {self.buttons.push(button)}
+        self.cursor_pos = Some(pos);
+      }
+      Event::MouseButton(button, Action::Release, _, pos) => {
+        self.buttons.retain(|&b| b != button);
+        self.cursor_pos = Some(pos);
+      }
+      Event::MouseMove(pos, _) | Event::Scroll(_, pos) | Event::FileDrop(_, pos) => self.cursor_pos = Some(pos),
+      Event::MouseLeave => self.cursor_pos = None,
+      // Releases aren't sent to a window that doesn't have the focus
+      Event::WindowFocus(false) => {
+        self.keys.clear();
+        self.buttons.clear();
+      }
+      _ => (),
+    }
+  }
+}
+
+// TODO: more events
+#[derive(Debug, Clone, PartialEq)]
 pub enum Event {
   // These are derived from raw GLFW events
   MouseButton(glfw::MouseButton, Action, glfw::Modifiers, Vec2<i32>),
   MouseMove(Vec2<i32>, Vec<glfw::MouseButton>),
+  /// A scroll wheel or trackpad moved. The first vector is how far, in steps of the wheel;
+  /// trackpads give fractions of a step. Positive y is up and positive x is right. The
+  /// second is the cursor position.
+  Scroll(Vec2<f64>, Vec2<i32>),
   Key(glfw::Key, glfw::Scancode, Action, glfw::Modifiers),
   Char(char),
+  /// Sent to a widget when the cursor moves onto it or something in it. At the window
+  /// level, this means the cursor entered the window.
+  MouseEnter,
+  /// Sent to a widget when the cursor moves off of it, including when the cursor leaves the
+  /// window. At the window level, this means the cursor left the window.
+  MouseLeave,
+  /// Sent to a widget when it gets the keyboard focus
+  FocusIn,
+  /// Sent to a widget when it loses the keyboard focus
+  FocusOut,
+  // These are made from the mouse events by the window, and go to the widget the button
+  // was pressed on
+  /// A button was pressed and released over the same widget without moving much. The count
+  /// is 1 for a single click, 2 for the second click of a double click, and so on.
+  Click(glfw::MouseButton, u32, Vec2<i32>),
+  /// Sent after the Click for the second click of a double click
+  DoubleClick(glfw::MouseButton, Vec2<i32>),
+  TripleClick(glfw::MouseButton, Vec2<i32>),
+  /// A button was held down without moving for `LONG_PRESS_TIME`. It isn't followed by a
+  /// Click when it's released.
+  LongPress(glfw::MouseButton, Vec2<i32>),
+  /// The cursor moved more than `DRAG_DISTANCE` with a button held; the position is where
+  /// the button was pressed
+  DragStart(glfw::MouseButton, Vec2<i32>),
+  Drag(glfw::MouseButton, Vec2<i32>),
+  DragEnd(glfw::MouseButton, Vec2<i32>),
+  /// A shortcut added with `GUIWindow::add_shortcut` was pressed. This goes to the focused
+  /// widget and its containers.
+  Shortcut(String),
+  /// Files were dragged onto the window and dropped at a position. This goes to the widget
+  /// under that position.
+  FileDrop(Vec<Path>, Vec2<i32>),
+  // These are only seen by `GUIWindow::get_events`
+  /// The user tried to close the window. It closes unless `glfw_window.set_should_close(false)`
+  /// is called, so apps can ask to save first.
+  CloseRequested,
+  /// The window gained (true) or lost (false) the input focus
+  WindowFocus(bool),
+  /// The window was minimized (true) or restored (false)
+  Iconify(bool),
+  /// The window's framebuffer changed size; this is in pixels
+  FramebufferResize(Vec2<i32>),
   Unknown,
   // These are higher-level events sent from widgets
   // Activate(Id),
 }
 
 impl Event {
-  // Note: for some events this looks at the window's current cursor position
-  fn from_glfw(event: glfw::WindowEvent, window: &glfw::Window) -> Event {
-    match event {
-      glfw::WindowEvent::MouseButton(button, action, mods) => {
+  // GLFW gives cursor positions in screen coordinates, and `cursor_scale` converts them to
+  // logical units. `input` is the state before this event; it gives the buttons held during a
+  // MouseMove, and the cursor position for events that don't carry one. The window's current
+  // cursor position is only used if no position has been tracked yet, since by the time the
+  // events are read the cursor may have moved on.
+  fn from_glfw(event: glfw::WindowEvent, window: &glfw::Window, cursor_scale: f32, input: &InputState) -> Event {
+    let to_logical = |x: f64, y: f64| Vec2((x as f32 * cursor_scale) as i32, (y as f32 * cursor_scale) as i32);
+    let tracked_pos = || match input.cursor_pos() {
+      Some(pos) => pos,
+      None => {
         let (cursor_x, cursor_y) = window.get_cursor_pos();
-        let cursor_pos = Vec2(cursor_x as i32, cursor_y as i32);
-        Event::MouseButton(button, Action::from_glfw(action), mods, cursor_pos)
-      },
+        to_logical(cursor_x, cursor_y)
+      }
+    };
+    match event {
+      glfw::WindowEvent::MouseButton(button, action, mods) =>
+        Event::MouseButton(button, Action::from_glfw(action), mods, tracked_pos()),
       glfw::WindowEvent::CursorPos(cursor_x, cursor_y) => {
-        let cursor_pos = Vec2(cursor_x as i32, cursor_y as i32);
-        let mut buttons = Vec::new();
-        // TODO: this isn't great
-        for i in range(0, 8) {
-          let button = FromPrimitive::from_u8(i).unwrap();
-          if window.get_mouse_button(button) != glfw::Action::Release {
-            buttons.push(button);
-          }
-        }
-        Event::MouseMove(cursor_pos, buttons)
+        let cursor_pos = to_logical(cursor_x, cursor_y);
+        Event::MouseMove(cursor_pos, input.pressed_buttons().to_vec())
       },
       glfw::WindowEvent::Key(key, scancode, action, mods) =>
         Event::Key(key, scancode, Action::from_glfw(action), mods),
       glfw::WindowEvent::Char(char) =>
         Event::Char(char),
+      glfw::WindowEvent::Scroll(x, y) => Event::Scroll(Vec2(x, y), tracked_pos()),
+      glfw::WindowEvent::FileDrop(paths) => Event::FileDrop(paths, tracked_pos()),
+      glfw::WindowEvent::Close => Event::CloseRequested,
+      glfw::WindowEvent::Focus(focused) => Event::WindowFocus(focused),
+      glfw::WindowEvent::Iconify(iconified) => Event::Iconify(iconified),
+      glfw::WindowEvent::FramebufferSize(width, height) => Event::FramebufferResize(Vec2(width, height)),
+      glfw::WindowEvent::CursorEnter(true) => Event::MouseEnter,
+      glfw::WindowEvent::CursorEnter(false) => Event::MouseLeave,
       _ => Event::Unknown,
     }
   }
 
+  // Whether this is sent to the focused widget
+  fn is_keyboard(&self) -> bool {
+    match *self {
+      Event::Key(..) | Event::Char(_) => true,
+      _ => false,
+    }
+  }
+
   fn position(&self) -> Option<Vec2<i32>> {
     match *self {
       Event::MouseButton(_, _, _, pos) => Some(pos),
       Event::MouseMove(pos, _) => Some(pos),
+      Event::Scroll(_, pos) => Some(pos),
+      Event::FileDrop(_, pos) => Some(pos),
+      Event::Click(_, _, pos) => Some(pos),
+      Event::DoubleClick(_, pos) | Event::TripleClick(_, pos) | Event::LongPress(_, pos) => Some(pos),
+      Event::DragStart(_, pos) | Event::Drag(_, pos) | Event::DragEnd(_, pos) => Some(pos),
       _ => None,
     }
   }
@@ -623,9 +3823,368 @@ impl Event {
         Event::MouseButton(button, action, mods, pos-widget_pos),
       &Event::MouseMove(pos, ref buttons) =>
         Event::MouseMove(pos-widget_pos, buttons.clone()),
+      &Event::Scroll(delta, pos) =>
+        Event::Scroll(delta, pos-widget_pos),
+      &Event::FileDrop(ref paths, pos) =>
+        Event::FileDrop(paths.clone(), pos-widget_pos),
+      &Event::Click(button, count, pos) => Event::Click(button, count, pos-widget_pos),
+      &Event::DoubleClick(button, pos) => Event::DoubleClick(button, pos-widget_pos),
+      &Event::TripleClick(button, pos) => Event::TripleClick(button, pos-widget_pos),
+      &Event::LongPress(button, pos) => Event::LongPress(button, pos-widget_pos),
+      &Event::DragStart(button, pos) => Event::DragStart(button, pos-widget_pos),
+      &Event::Drag(button, pos) => Event::Drag(button, pos-widget_pos),
+      &Event::DragEnd(button, pos) => Event::DragEnd(button, pos-widget_pos),
       x => x.clone(),
     }
   }
 }
 
 type EventHandler<'a> = Fn(Event) -> bool + 'a;
+
+
+#[cfg(test)]
+mod tests {
+  use glfw;
+  use std::rc::Rc;
+  use std::cell::{Cell, RefCell};
+  use std::mem;
+  use vecmat::*;
+  use text::*;
+  use color::*;
+  use util::*;
+  use widgets::*;
+  use super::*;
+
+  // Every character is 10 units wide, and lines are as tall as the font's size
+  struct TestMeasure;
+
+  impl Measure for TestMeasure {
+    fn string_size(&mut self, font: FontKey, text: &str) -> Vec2<i32> {
+      Vec2(text.chars().count() as i32 * 10, font.size)
+    }
+    fn vert_advance(&mut self, font: FontKey) -> i32 {font.size}
+  }
+
+  // Counts how many times it's measured
+  struct CountingWidget {
+    id: Id,
+    size: Vec2<i32>,
+    dirty: bool,
+    measured: Rc<Cell<u32>>,
+  }
+
+  impl CountingWidget {
+    fn new(size: Vec2<i32>, measured: Rc<Cell<u32>>) -> Rc<RefCell<CountingWidget>> {
+      Rc::new(RefCell::new(CountingWidget{id: next_id(), size: size, dirty: true, measured: measured}))
+    }
+
+    fn resize(&mut self, size: Vec2<i32>) {
+      self.size = size;
+      self.dirty = true;
+    }
+  }
+
+  impl Widget for CountingWidget {
+    fn id(&self) -> Id {self.id}
+    fn draw(&mut self, pos: Vec2<i32>, size: Vec2<i32>, window: &mut GUIWindow) {}
+    fn min_size(&self, measure: &mut Measure) -> Vec2<i32> {
+      self.measured.set(self.measured.get() + 1);
+      self.size
+    }
+    fn take_dirty(&mut self) -> bool {mem::replace(&mut self.dirty, false)}
+  }
+
+  #[test]
+  fn vpanel_spacing() {
+    let mut a = EmptyWidget::new(Vec2(20, 10));
+    let mut b = EmptyWidget::new(Vec2(20, 10));
+    let (a_id, b_id) = (a.id(), b.id());
+    let mut state = LayoutState::new();
+    VPanel(Leading, Spacing::new(5, 10), vec![(LWidget(&mut a), 0.0), (LWidget(&mut b), 1.0)])
+      .arrange(Vec2(100, 100), &mut state, &mut TestMeasure);
+    assert_eq!(state.widget_pos(a_id), Some(Vec2(5, 5)));
+    assert_eq!(state.widget_size(a_id), Some(Vec2(90, 10)));
+    assert_eq!(state.widget_pos(b_id), Some(Vec2(5, 25)));
+    assert_eq!(state.widget_size(b_id), Some(Vec2(90, 70)));
+  }
+
+  #[test]
+  fn hpanel_flex() {
+    let mut a = EmptyWidget::new(Vec2(10, 10));
+    let mut b = EmptyWidget::new(Vec2(10, 10));
+    let (a_id, b_id) = (a.id(), b.id());
+    let mut state = LayoutState::new();
+    HPanel(Leading, Spacing::none(), vec![(LWidget(&mut a), 1.0), (LWidget(&mut b), 3.0)])
+      .arrange(Vec2(100, 20), &mut state, &mut TestMeasure);
+    assert_eq!(state.widget_pos(a_id), Some(Vec2(0, 0)));
+    assert_eq!(state.widget_size(a_id), Some(Vec2(30, 20)));
+    assert_eq!(state.widget_pos(b_id), Some(Vec2(30, 0)));
+    assert_eq!(state.widget_size(b_id), Some(Vec2(70, 20)));
+  }
+
+  #[test]
+  fn grid_span() {
+    let mut a = EmptyWidget::new(Vec2(30, 10));
+    let mut b = EmptyWidget::new(Vec2(20, 10));
+    let mut c = EmptyWidget::new(Vec2(10, 10));
+    let (a_id, b_id, c_id) = (a.id(), b.id(), c.id());
+    let mut state = LayoutState::new();
+    let size = Grid(vec![0.0, 0.0], vec![1.0, 1.0], vec![
+      GridCell::new(0, 0, LWidget(&mut a)),
+      GridCell::new(0, 1, LWidget(&mut b)),
+      GridCell::new(1, 0, LWidget(&mut c)).span(1, 2),
+    ]).arrange(Vec2(100, 0), &mut state, &mut TestMeasure);
+    assert_eq!(size, Vec2(100, 20));
+    assert_eq!(state.widget_pos(a_id), Some(Vec2(0, 0)));
+    assert_eq!(state.widget_size(a_id), Some(Vec2(55, 10)));
+    assert_eq!(state.widget_pos(b_id), Some(Vec2(55, 0)));
+    assert_eq!(state.widget_size(b_id), Some(Vec2(45, 10)));
+    assert_eq!(state.widget_pos(c_id), Some(Vec2(0, 10)));
+    assert_eq!(state.widget_size(c_id), Some(Vec2(100, 10)));
+  }
+
+  #[test]
+  fn grid_preferred_columns() {
+    // A column that doesn't flex still grows to fit its label on one line
+    let mut label = LabelWidget::new_wrapped(FontKey::new(10), "aa bb", Color::black());
+    let mut field = EmptyWidget::new(Vec2(10, 10));
+    let (label_id, field_id) = (label.id(), field.id());
+    let mut state = LayoutState::new();
+    Grid(vec![0.0], vec![0.0, 1.0], vec![
+      GridCell::new(0, 0, LWidget(&mut label)),
+      GridCell::new(0, 1, LWidget(&mut field)),
+    ]).arrange(Vec2(100, 0), &mut state, &mut TestMeasure);
+    assert_eq!(state.widget_size(label_id), Some(Vec2(50, 10)));
+    assert_eq!(state.widget_pos(field_id), Some(Vec2(50, 0)));
+    assert_eq!(state.widget_size(field_id), Some(Vec2(50, 10)));
+  }
+
+  #[test]
+  fn grid_max_size() {
+    // A grid whose columns don't flex can't use more than its preferred width, so its share
+    // of the panel goes to the other child
+    let mut a = EmptyWidget::new(Vec2(20, 10));
+    let mut b = EmptyWidget::new(Vec2(20, 10));
+    let b_id = b.id();
+    let mut state = LayoutState::new();
+    HPanel(Leading, Spacing::none(), vec![
+      (Grid(vec![0.0], vec![0.0], vec![GridCell::new(0, 0, LWidget(&mut a))]), 1.0),
+      (LWidget(&mut b), 1.0),
+    ]).arrange(Vec2(100, 10), &mut state, &mut TestMeasure);
+    assert_eq!(state.widget_pos(b_id), Some(Vec2(20, 0)));
+    assert_eq!(state.widget_size(b_id), Some(Vec2(80, 10)));
+  }
+
+  #[test]
+  fn wrapped_label() {
+    // "aa bb" just fits in 50 units, so "cc" goes on a second line
+    let mut label = LabelWidget::new_wrapped(FontKey::new(10), "aa bb cc", Color::black());
+    let label_id = label.id();
+    let mut state = LayoutState::new();
+    VPanel(Leading, Spacing::none(), vec![(LWidget(&mut label), 0.0)])
+      .arrange(Vec2(50, 0), &mut state, &mut TestMeasure);
+    assert_eq!(state.widget_size(label_id), Some(Vec2(50, 20)));
+  }
+
+  #[test]
+  fn wrapped_label_paragraphs() {
+    // Each paragraph starts a new line even though they'd fit on one
+    let mut label = LabelWidget::new_wrapped(FontKey::new(10), "aa\nbb\n\ncc", Color::black());
+    let label_id = label.id();
+    let mut state = LayoutState::new();
+    VPanel(Leading, Spacing::none(), vec![(LWidget(&mut label), 0.0)])
+      .arrange(Vec2(100, 0), &mut state, &mut TestMeasure);
+    assert_eq!(state.widget_size(label_id), Some(Vec2(100, 40)));
+  }
+
+  #[test]
+  fn tabs_right_to_left() {
+    let mut tabs = TabState::new(FontKey::new(10), Color::black(), Color::white());
+    let mut a = EmptyWidget::new(Vec2(10, 10));
+    let mut b = EmptyWidget::new(Vec2(10, 10));
+    let mut state = LayoutState::new();
+    Directed(Direction::RightToLeft, Box::new(Tabs(&mut tabs, vec![("ab", LWidget(&mut a)), ("c", LWidget(&mut b))])))
+      .arrange(Vec2(100, 100), &mut state, &mut TestMeasure);
+    // The first tab is on the right
+    assert_eq!(tabs.tab_extents(&mut TestMeasure, 100), vec![(64, 36), (38, 26)]);
+  }
+
+  #[test]
+  fn tree_remeasures_only_changes() {
+    let measured = Rc::new(Cell::new(0));
+    let a = CountingWidget::new(Vec2(10, 10), measured.clone());
+    let b = CountingWidget::new(Vec2(10, 10), measured.clone());
+    let b_id = b.id();
+    let row = |widget: &Rc<RefCell<CountingWidget>>|
+      (Node::new(NodeKind::HPanel(Leading, Spacing::none(), vec![(Node::widget(widget.clone()), 0.0)])), 0.0);
+    let mut root = Node::new(NodeKind::VPanel(Leading, Spacing::none(), vec![row(&a), row(&b)]));
+    let mut state = LayoutState::new();
+    root.as_layout().arrange(Vec2(100, 100), &mut state, &mut TestMeasure);
+    assert_eq!(state.widget_pos(b_id), Some(Vec2(0, 10)));
+
+    // Nothing changed, so nothing is measured
+    measured.set(0);
+    root.as_layout().arrange(Vec2(100, 100), &mut state, &mut TestMeasure);
+    assert_eq!(measured.get(), 0);
+    assert_eq!(state.widget_pos(b_id), Some(Vec2(0, 10)));
+
+    // Growing one widget moves the other one down
+    a.borrow_mut().resize(Vec2(10, 30));
+    root.as_layout().arrange(Vec2(100, 100), &mut state, &mut TestMeasure);
+    assert_eq!(state.widget_pos(b_id), Some(Vec2(0, 30)));
+  }
+
+  #[test]
+  fn tree_tabs_follow_direction() {
+    let page = CountingWidget::new(Vec2(10, 10), Rc::new(Cell::new(0)));
+    let tabs = TabState::new(FontKey::new(10), Color::black(), Color::white());
+    let mut root = Node::new(NodeKind::Tabs(tabs, vec![("ab".to_string(), Node::widget(page))]));
+    let mut state = LayoutState::new();
+    root.as_layout().arrange(Vec2(100, 100), &mut state, &mut TestMeasure);
+    // Only the direction changes, not anything in the tree
+    state.direction = Direction::RightToLeft;
+    root.as_layout().arrange(Vec2(100, 100), &mut state, &mut TestMeasure);
+    match root.kind {
+      NodeKind::Tabs(ref tabs, _) => assert_eq!(tabs.tab_extents(&mut TestMeasure, 100), vec![(64, 36)]),
+      _ => unreachable!(),
+    }
+  }
+
+  fn press(pos: Vec2<i32>) -> Event {
+    Event::MouseButton(glfw::MouseButtonLeft, Action::Press, glfw::Modifiers::empty(), pos)
+  }
+
+  fn release(pos: Vec2<i32>) -> Event {
+    Event::MouseButton(glfw::MouseButtonLeft, Action::Release, glfw::Modifiers::empty(), pos)
+  }
+
+  fn no_events() -> Vec<Event> {vec![]}
+
+  // Presses and releases the left button at `pos` at `time`, and returns the gesture events
+  fn click(gestures: &mut GestureRecognizer, pos: Vec2<i32>, time: f64) -> Vec<Event> {
+    let mut events = gestures.event(&press(pos), time);
+    events.extend(gestures.event(&release(pos), time).into_iter());
+    events
+  }
+
+  #[test]
+  fn click_counting() {
+    let mut gestures = GestureRecognizer::new();
+    let pos = Vec2(10, 10);
+    assert_eq!(click(&mut gestures, pos, 0.0), vec![Event::Click(glfw::MouseButtonLeft, 1, pos)]);
+    assert_eq!(click(&mut gestures, pos, 0.1),
+      vec![Event::Click(glfw::MouseButtonLeft, 2, pos), Event::DoubleClick(glfw::MouseButtonLeft, pos)]);
+    assert_eq!(click(&mut gestures, pos, 0.2),
+      vec![Event::Click(glfw::MouseButtonLeft, 3, pos), Event::TripleClick(glfw::MouseButtonLeft, pos)]);
+    assert_eq!(click(&mut gestures, pos, 0.3), vec![Event::Click(glfw::MouseButtonLeft, 4, pos)]);
+  }
+
+  #[test]
+  fn multi_click_limits() {
+    let mut gestures = GestureRecognizer::new();
+    let pos = Vec2(10, 10);
+    click(&mut gestures, pos, 0.0);
+    // Too long after the first click
+    assert_eq!(click(&mut gestures, pos, MULTI_CLICK_TIME + 0.1), vec![Event::Click(glfw::MouseButtonLeft, 1, pos)]);
+    // Too far from the last click
+    let far = pos + Vec2(MULTI_CLICK_DISTANCE + 1, 0);
+    assert_eq!(click(&mut gestures, far, MULTI_CLICK_TIME + 0.2), vec![Event::Click(glfw::MouseButtonLeft, 1, far)]);
+    // Just close enough
+    let near = far + Vec2(0, MULTI_CLICK_DISTANCE);
+    assert_eq!(click(&mut gestures, near, MULTI_CLICK_TIME + 0.3),
+      vec![Event::Click(glfw::MouseButtonLeft, 2, near), Event::DoubleClick(glfw::MouseButtonLeft, near)]);
+  }
+
+  #[test]
+  fn long_press_suppresses_click() {
+    let mut gestures = GestureRecognizer::new();
+    let pos = Vec2(10, 10);
+    assert_eq!(gestures.event(&press(pos), 0.0), no_events());
+    assert_eq!(gestures.check_long_press(LONG_PRESS_TIME / 2.0), no_events());
+    assert_eq!(gestures.check_long_press(LONG_PRESS_TIME), vec![Event::LongPress(glfw::MouseButtonLeft, pos)]);
+    // It's only sent once
+    assert_eq!(gestures.check_long_press(LONG_PRESS_TIME * 2.0), no_events());
+    assert_eq!(gestures.event(&release(pos), LONG_PRESS_TIME * 2.0), no_events());
+    assert_eq!(gestures.held_button(), None);
+  }
+
+  #[test]
+  fn drag_threshold() {
+    let mut gestures = GestureRecognizer::new();
+    let start = Vec2(10, 10);
+    assert_eq!(gestures.event(&press(start), 0.0), no_events());
+    // Moving this far could still be a click
+    let near = start + Vec2(DRAG_DISTANCE, -DRAG_DISTANCE);
+    assert_eq!(gestures.event(&Event::MouseMove(near, vec![glfw::MouseButtonLeft]), 0.1), no_events());
+    let far = start + Vec2(0, DRAG_DISTANCE + 1);
+    assert_eq!(gestures.event(&Event::MouseMove(far, vec![glfw::MouseButtonLeft]), 0.2),
+      vec![Event::DragStart(glfw::MouseButtonLeft, start), Event::Drag(glfw::MouseButtonLeft, far)]);
+    // Once it's a drag, every move is part of it, and it can't turn into a long press
+    assert_eq!(gestures.event(&Event::MouseMove(start, vec![glfw::MouseButtonLeft]), 0.3),
+      vec![Event::Drag(glfw::MouseButtonLeft, start)]);
+    assert_eq!(gestures.check_long_press(LONG_PRESS_TIME * 2.0), no_events());
+    assert_eq!(gestures.event(&release(start), LONG_PRESS_TIME * 2.0), vec![Event::DragEnd(glfw::MouseButtonLeft, start)]);
+  }
+
+  #[test]
+  fn cancelled_click() {
+    let mut gestures = GestureRecognizer::new();
+    let pos = Vec2(10, 10);
+    gestures.event(&press(pos), 0.0);
+    gestures.cancel_click();
+    assert_eq!(gestures.event(&release(pos), 0.1), no_events());
+    // The cancelled click doesn't count towards a double click
+    assert_eq!(click(&mut gestures, pos, 0.2), vec![Event::Click(glfw::MouseButtonLeft, 1, pos)]);
+  }
+
+  fn key(key: glfw::Key, action: Action) -> Event {
+    Event::Key(key, 0, action, glfw::Modifiers::empty())
+  }
+
+  #[test]
+  fn input_keys() {
+    let mut input = InputState::new();
+    input.update(&key(glfw::Key::A, Action::Press));
+    input.update(&key(glfw::Key::B, Action::Press));
+    input.update(&key(glfw::Key::A, Action::Repeat));
+    assert_eq!(input.pressed_keys().to_vec(), vec![glfw::Key::A, glfw::Key::B]);
+    input.update(&key(glfw::Key::A, Action::Release));
+    assert!(!input.key_down(glfw::Key::A));
+    assert_eq!(input.pressed_keys().to_vec(), vec![glfw::Key::B]);
+    // A repeat whose press was missed still means the key is down
+    input.update(&key(glfw::Key::C, Action::Repeat));
+    assert!(input.key_down(glfw::Key::C));
+  }
+
+  #[test]
+  fn input_unknown_keys() {
+    let mut input = InputState::new();
+    input.update(&Event::Key(glfw::Key::Unknown, 1, Action::Press, glfw::Modifiers::empty()));
+    input.update(&Event::Key(glfw::Key::Unknown, 2, Action::Press, glfw::Modifiers::empty()));
+    input.update(&Event::Key(glfw::Key::Unknown, 1, Action::Release, glfw::Modifiers::empty()));
+    assert!(input.pressed_keys().is_empty());
+  }
+
+  #[test]
+  fn input_modifiers() {
+    let mut input = InputState::new();
+    input.update(&key(glfw::Key::LeftShift, Action::Press));
+    input.update(&key(glfw::Key::RightControl, Action::Press));
+    assert!(input.modifiers() == glfw::Shift | glfw::Control);
+    input.update(&key(glfw::Key::LeftShift, Action::Release));
+    assert!(input.modifiers() == glfw::Control);
+  }
+
+  #[test]
+  fn input_cleared_when_unfocused() {
+    let mut input = InputState::new();
+    input.update(&key(glfw::Key::LeftAlt, Action::Press));
+    input.update(&press(Vec2(10, 10)));
+    input.update(&Event::WindowFocus(false));
+    assert!(input.pressed_keys().is_empty());
+    assert!(input.pressed_buttons().is_empty());
+    assert!(input.modifiers().is_empty());
+    // The cursor hasn't gone anywhere
+    assert_eq!(input.cursor_pos(), Some(Vec2(10, 10)));
+  }
+}
@@ -16,6 +16,7 @@ pub mod gl_program;
 pub mod mesh;
 pub mod framebuffer;
 pub mod gui;
+pub mod record;
 pub mod util;
 pub mod widgets;
 pub mod new_gl_program;
@@ -0,0 +1,306 @@
+// Saving the events a window gets to a file, and playing them back later
+//
+// A recording is a text file. After a header line, each frame the window drew is a line
+// "frame <number> <time> <width> <height> <scale>", giving the logical size the window was
+// laid out at and its content scale, followed by a line "event <time> <kind> <fields...>" for
+// each event read that frame. Only the events read from GLFW are saved; clicks, focus changes and
+// the like are made from them again when they're played back.
+
+use std::old_io::{File, IoResult};
+use std::num::FromPrimitive;
+use std::str::FromStr;
+use std::char;
+use glfw;
+use vecmat::*;
+
+use gui::{Event, Action};
+
+const HEADER: &'static str = "gui-recording 2";
+
+/// Writes the events a window gets to a file, one frame at a time
+pub struct Recorder {
+  file: File,
+  frame: u64,
+}
+
+impl Recorder {
+  pub fn new(path: &Path) -> IoResult<Recorder> {
+    let mut file = try!(File::create(path));
+    try!(file.write_line(HEADER));
+    Ok(Recorder {file: file, frame: 0})
+  }
+
+  /// Saves the events read in a frame, along with the times GLFW gave them and the size and
+  /// content scale the frame was laid out at. Frames without events are saved too, since the
+  /// time still matters for long presses.
+  pub fn record_frame(&mut self, time: f64, window_size: Vec2<i32>, content_scale: f32, events: &[Event], event_times: &[f64]) -> IoResult<()> {
+    let mut out = format!("frame {} {} {} {} {}\n", self.frame, time, window_size.x, window_size.y, content_scale);
+    for (event, &event_time) in events.iter().zip(event_times.iter()) {
+      out.push_str(format!("event {} {}\n", event_time, write_event(event)).as_slice());
+    }
+    // Written a frame at a time so a crash loses at most one frame
+    try!(self.file.write_str(out.as_slice()));
+    try!(self.file.flush());
+    self.frame += 1;
+    Ok(())
+  }
+}
+
+/// A frame read from a recording
+#[derive(Debug, Clone)]
+pub struct RecordedFrame {
+  pub frame: u64,
+  pub time: f64,
+  /// The window's size in logical units
+  pub window_size: Vec2<i32>,
+  pub content_scale: f32,
+  pub events: Vec<Event>,
+  pub event_times: Vec<f64>,
+}
+
+/// A recording loaded from a file, which is played back a frame at a time
+pub struct Recording {
+  frames: Vec<RecordedFrame>,
+  next: usize,
+}
+
+impl Recording {
+  /// Fails if the file can't be read or isn't a recording
+  pub fn load(path: &Path) -> Result<Recording, String> {
+    let contents = match File::open(path).read_to_string() {
+      Ok(contents) => contents,
+      Err(err) => return Err(format!("Failed to read recording {}: {}", path.display(), err)),
+    };
+    Recording::parse(contents.as_slice()).map_err(|err| format!("{} in {}", err, path.display()))
+  }
+
+  /// Reads a recording from the contents of a file
+  pub fn parse(contents: &str) -> Result<Recording, String> {
+    let mut lines = contents.lines().enumerate();
+    match lines.next() {
+      Some((_, line)) if line == HEADER => (),
+      _ => return Err("Missing recording header".to_string()),
+    }
+    let mut frames: Vec<RecordedFrame> = vec![];
+    for (i, line) in lines {
+      let (kind, rest) = split_field(line);
+      let ok = match kind {
+        "frame" => {
+          let fields: Vec<&str> = rest.split(' ').collect();
+          let field = |i: usize| fields.get(i).map(|field| *field).unwrap_or("");
+          match (parse::<u64>(field(0)), parse::<f64>(field(1)), parse::<i32>(field(2)), parse::<i32>(field(3)),
+              parse::<f32>(field(4))) {
+            (Some(frame), Some(time), Some(width), Some(height), Some(scale)) if fields.len() == 5 => {
+              frames.push(RecordedFrame {frame: frame, time: time, window_size: Vec2(width, height), content_scale: scale,
+                events: vec![], event_times: vec![]});
+              true
+            }
+            _ => false,
+          }
+        }
+        "event" => {
+          let (time, rest) = split_field(rest);
+          match (frames.last_mut(), parse::<f64>(time), read_event(rest)) {
+            (Some(frame), Some(time), Some(event)) => {
+              frame.events.push(event);
+              frame.event_times.push(time);
+              true
+            }
+            _ => false,
+          }
+        }
+        "" => true,
+        _ => false,
+      };
+      if !ok {
+        return Err(format!("Bad line {}: {}", i + 1, line));
+      }
+    }
+    Ok(Recording {frames: frames, next: 0})
+  }
+
+  /// The next frame to play, or None when the recording is over
+  pub fn next_frame(&mut self) -> Option<RecordedFrame> {
+    let frame = self.frames.get(self.next).map(|frame| frame.clone());
+    if frame.is_some() {self.next += 1}
+    frame
+  }
+
+  pub fn finished(&self) -> bool {
+    self.next >= self.frames.len()
+  }
+}
+
+// Splits the first space-separated field off of a string
+fn split_field(string: &str) -> (&str, &str) {
+  match string.find(' ') {
+    Some(i) => (&string[..i], &string[i+1..]),
+    None => (string, ""),
+  }
+}
+
+fn parse<T: FromStr>(string: &str) -> Option<T> {
+  string.parse().ok()
+}
+
+fn write_action(action: Action) -> &'static str {
+  match action {
+    Action::Press => "press",
+    Action::Release => "release",
+    Action::Repeat => "repeat",
+  }
+}
+
+fn read_action(string: &str) -> Option<Action> {
+  match string {
+    "press" => Some(Action::Press),
+    "release" => Some(Action::Release),
+    "repeat" => Some(Action::Repeat),
+    _ => None,
+  }
+}
+
+fn write_event(event: &Event) -> String {
+  match *event {
+    Event::MouseButton(button, action, mods, pos) =>
+      format!("mouse_button {} {} {} {} {}", button as i32, write_action(action), mods.bits(), pos.x, pos.y),
+    Event::MouseMove(pos, ref buttons) => {
+      let mut out = format!("mouse_move {} {}", pos.x, pos.y);
+      for &button in buttons.iter() {
+        out.push_str(format!(" {}", button as i32).as_slice());
+      }
+      out
+    }
+    Event::Scroll(delta, pos) => format!("scroll {} {} {} {}", delta.x, delta.y, pos.x, pos.y),
+    Event::Key(key, scancode, action, mods) =>
+      format!("key {} {} {} {}", key as i32, scancode, write_action(action), mods.bits()),
+    Event::Char(char) => format!("char {}", char as u32),
+    Event::MouseEnter => "mouse_enter".to_string(),
+    Event::MouseLeave => "mouse_leave".to_string(),
+    // Paths go last since they can have spaces in them; they're separated by tabs, which
+    // are rare enough in paths that they aren't supported
+    Event::FileDrop(ref paths, pos) => {
+      let paths: Vec<String> = paths.iter().map(|path| format!("{}", path.display())).collect();
+      format!("file_drop {} {} {}", pos.x, pos.y, paths.connect("\t"))
+    }
+    Event::CloseRequested => "close_requested".to_string(),
+    Event::WindowFocus(focused) => format!("window_focus {}", focused),
+    Event::Iconify(iconified) => format!("iconify {}", iconified),
+    Event::FramebufferResize(size) => format!("framebuffer_resize {} {}", size.x, size.y),
+    // The rest are made by the window rather than read from GLFW
+    _ => "unknown".to_string(),
+  }
+}
+
+fn read_event(string: &str) -> Option<Event> {
+  let (kind, rest) = split_field(string);
+  let fields: Vec<&str> = if rest.is_empty() {vec![]} else {rest.split(' ').collect()};
+  let int = |i: usize| fields.get(i).and_then(|field| parse::<i32>(*field));
+  let float = |i: usize| fields.get(i).and_then(|field| parse::<f64>(*field));
+  let boolean = |i: usize| fields.get(i).and_then(|field| parse::<bool>(*field));
+  let mods = |i: usize| int(i).and_then(|bits| glfw::Modifiers::from_bits(bits));
+  let button = |i: usize| int(i).and_then(|n| FromPrimitive::from_i32(n));
+  let action = |i: usize| fields.get(i).and_then(|field| read_action(*field));
+  let vec = |i: usize| match (int(i), int(i+1)) {
+    (Some(x), Some(y)) => Some(Vec2(x, y)),
+    _ => None,
+  };
+  match kind {
+    "mouse_button" => match (button(0), action(1), mods(2), vec(3)) {
+      (Some(button), Some(action), Some(mods), Some(pos)) => Some(Event::MouseButton(button, action, mods, pos)),
+      _ => None,
+    },
+    "mouse_move" => {
+      let buttons: Vec<Option<glfw::MouseButton>> = range(2, fields.len()).map(|i| button(i)).collect();
+      match vec(0) {
+        Some(pos) if buttons.iter().all(|button| button.is_some()) =>
+          Some(Event::MouseMove(pos, buttons.into_iter().map(|button| button.unwrap()).collect())),
+        _ => None,
+      }
+    }
+    "scroll" => match (float(0), float(1), vec(2)) {
+      (Some(x), Some(y), Some(pos)) => Some(Event::Scroll(Vec2(x, y), pos)),
+      _ => None,
+    },
+    "key" => match (int(0).and_then(|n| FromPrimitive::from_i32(n)), int(1), action(2), mods(3)) {
+      (Some(key), Some(scancode), Some(action), Some(mods)) => Some(Event::Key(key, scancode, action, mods)),
+      _ => None,
+    },
+    "char" => fields.get(0).and_then(|field| parse::<u32>(*field)).and_then(|n| char::from_u32(n)).map(|char| Event::Char(char)),
+    "mouse_enter" => Some(Event::MouseEnter),
+    "mouse_leave" => Some(Event::MouseLeave),
+    "file_drop" => {
+      let (x, rest) = split_field(rest);
+      let (y, rest) = split_field(rest);
+      let paths = rest.split('\t').filter(|path| !path.is_empty()).map(|path| Path::new(path)).collect();
+      match (parse::<i32>(x), parse::<i32>(y)) {
+        (Some(x), Some(y)) => Some(Event::FileDrop(paths, Vec2(x, y))),
+        _ => None,
+      }
+    }
+    "close_requested" => Some(Event::CloseRequested),
+    "window_focus" => boolean(0).map(|focused| Event::WindowFocus(focused)),
+    "iconify" => boolean(0).map(|iconified| Event::Iconify(iconified)),
+    "framebuffer_resize" => vec(0).map(|size| Event::FramebufferResize(size)),
+    "unknown" => Some(Event::Unknown),
+    _ => None,
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use glfw;
+  use vecmat::*;
+  use gui::{Event, Action};
+  use super::{write_event, read_event, Recording, HEADER};
+
+  #[test]
+  fn events_round_trip() {
+    let events = vec![
+      Event::MouseButton(glfw::MouseButtonLeft, Action::Press, glfw::Shift | glfw::Control, Vec2(3, -4)),
+      Event::MouseButton(glfw::MouseButtonRight, Action::Release, glfw::Modifiers::empty(), Vec2(0, 0)),
+      Event::MouseMove(Vec2(10, 20), vec![glfw::MouseButtonLeft, glfw::MouseButtonRight]),
+      Event::MouseMove(Vec2(1, 2), vec![]),
+      Event::Scroll(Vec2(0.25, -1.5), Vec2(5, 6)),
+      Event::Key(glfw::Key::A, 30, Action::Repeat, glfw::Alt),
+      Event::Char('é'),
+      Event::MouseEnter,
+      Event::MouseLeave,
+      Event::FileDrop(vec![Path::new("/tmp/my file.txt"), Path::new("/home/user/a b/c")], Vec2(7, 8)),
+      Event::CloseRequested,
+      Event::WindowFocus(false),
+      Event::Iconify(true),
+      Event::FramebufferResize(Vec2(1600, 1200)),
+      Event::Unknown,
+    ];
+    for event in events.into_iter() {
+      let line = write_event(&event);
+      assert_eq!(read_event(line.as_slice()), Some(event));
+    }
+  }
+
+  #[test]
+  fn load_recording() {
+    let contents = format!("{}\nframe 0 1.5 800 600 2\nevent 1.25 mouse_enter\nevent 1.5 char 97\n\nframe 1 1.75 800 600 2\n\
+      frame 2 2 640 480 1\nevent 2 close_requested\n", HEADER);
+    let mut recording = Recording::parse(contents.as_slice()).unwrap();
+    let first = recording.next_frame().unwrap();
+    assert_eq!((first.frame, first.time, first.window_size, first.content_scale), (0, 1.5, Vec2(800, 600), 2.0));
+    assert_eq!(first.events, vec![Event::MouseEnter, Event::Char('a')]);
+    assert_eq!(first.event_times, vec![1.25, 1.5]);
+    assert!(recording.next_frame().unwrap().events.is_empty());
+    let last = recording.next_frame().unwrap();
+    assert_eq!((last.window_size, last.content_scale), (Vec2(640, 480), 1.0));
+    assert_eq!(last.events, vec![Event::CloseRequested]);
+    assert!(recording.finished());
+    assert!(recording.next_frame().is_none());
+
+    // A bad line anywhere fails the whole recording, saying which line it was
+    let bad = format!("{}\nframe 0 1.5 800 600 2\nevent 1.25 mouse_enter\nevent 1.5 teleport 3\nframe 1 1.75 800 600 2\n", HEADER);
+    let err = Recording::parse(bad.as_slice()).err().unwrap();
+    assert!(err.as_slice().contains("line 4"));
+    assert!(Recording::parse("frame 0 1.5 800 600 2\n").is_err());
+    assert!(Recording::load(&Path::new("/nonexistent/recording")).is_err());
+  }
+}
@@ -64,6 +64,7 @@ pub enum GLFlag {
   Blend,
   Multisample,
   CullFace,
+  ScissorTest,
 }
 
 impl GLFlag {
@@ -73,6 +74,7 @@ impl GLFlag {
       Blend => gl::BLEND,
       Multisample => gl::MULTISAMPLE,
       CullFace => gl::CULL_FACE,
+      ScissorTest => gl::SCISSOR_TEST,
     }
   }
 
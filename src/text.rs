@@ -11,6 +11,7 @@ use framebuffer::*;
 use mesh::*;
 use gl_program::*;
 use color::*;
+use util::*;
 
 /// Note: only create one of these or it might behave strangely
 pub struct FontLoader {
@@ -26,16 +27,11 @@ impl FontLoader {
 // TODO: Font should manage its own programs
 impl Font_ {
   // TODO: should this be a method on FontLoader?
-  fn new(loader: &FontLoader, path: &Path, size: i32, cache_program: Rc<GLProgram>, render_program: Rc<GLProgram>) -> Font_ {
-    let mut face = match loader.freetype.new_face(path, 0) {
+  fn new(loader: &FontLoader, path: &Path, size: i32, scale: f32, cache_program: Rc<GLProgram>, render_program: Rc<GLProgram>) -> Font_ {
+    let face = match loader.freetype.new_face(path, 0) {
       Ok(face) => face,
       Err(err) => panic!("Unable to load font at {:?} because {:?}", path, err),
     };
-    face.set_pixel_sizes(0, size as u32).unwrap();
-
-    let size_metrics = unsafe {(*face.raw().size).metrics};
-    let vert_advance = size_metrics.height / 64;
-    let descender = size_metrics.descender / 64;
 
     let framebuffer = Framebuffer::new(1024, 1024, gl::RED, false);
     let cache_mesh = Mesh::new(cache_program.clone(), Primitive::Triangles, MeshUsage::StreamDraw);
@@ -44,9 +40,38 @@ impl Font_ {
     let render_program_matrix_uni = Mat4Uniform::new("matrix", render_program.clone());
     // TODO: I probably don't need to store the matrix uniforms
     cache_program_matrix_uni.set(Mat4::ortho(framebuffer.tex.size.x as f32, framebuffer.tex.size.y as f32));
-    Font_ {face: face, size: size, vert_advance: vert_advance,
-        descender: descender, framebuffer: framebuffer,
-        glyphs: HashMap::new(), kerning: HashMap::new(), cur_x: 0, cur_y: 0, cache_mesh: cache_mesh, cache_program: cache_program, cache_program_matrix_uni: cache_program_matrix_uni, render_mesh: render_mesh, render_program: render_program, render_program_matrix_uni: render_program_matrix_uni}
+    let mut font = Font_ {face: face, size: size, scale: 0.0, vert_advance: 0,
+        descender: 0, framebuffer: framebuffer,
+        glyphs: HashMap::new(), kerning: HashMap::new(), cur_x: 0, cur_y: 0, cache_mesh: cache_mesh, cache_program: cache_program, cache_program_matrix_uni: cache_program_matrix_uni, render_mesh: render_mesh, render_program: render_program, render_program_matrix_uni: render_program_matrix_uni};
+    font.set_scale(scale);
+    font
+  }
+
+  // Glyphs are rendered at `size * scale` pixels, so they stay sharp on high DPI displays.
+  // Changing the scale throws away all the cached glyphs.
+  fn set_scale(&mut self, scale: f32) {
+    if scale == self.scale {return}
+    self.scale = scale;
+    self.face.set_pixel_sizes(0, self.pixel_size() as u32).unwrap();
+
+    let size_metrics = unsafe {(*self.face.raw().size).metrics};
+    self.vert_advance = size_metrics.height / 64;
+    self.descender = size_metrics.descender / 64;
+
+    self.framebuffer = Framebuffer::new(1024, 1024, gl::RED, false);
+    self.glyphs.clear();
+    self.kerning.clear();
+    self.cur_x = 0;
+    self.cur_y = 0;
+  }
+
+  fn pixel_size(&self) -> i32 {
+    (self.size as f32 * self.scale).round() as i32
+  }
+
+  // Converts a distance in pixels to logical units
+  fn logical(&self, pixels: i32) -> i32 {
+    (pixels as f32 / self.scale).round() as i32
   }
 
   fn load_glyph(&mut self, c: char) -> Glyph {
@@ -127,6 +152,7 @@ impl Font_ {
 
   // TODO: add support for background colors
   // TODO: get rid of window_size parameter
+  // `loc` and `window_size` are in logical units; everything else here is in pixels
   fn draw_string(&mut self, str: &str, loc: Vec2<i32>, color: Color<f32>, window_size: Vec2<i32>) {
     for c in str.chars() {self.cache_glyph(c);}
     self.framebuffer.tex.bind(0);
@@ -134,6 +160,9 @@ impl Font_ {
     // TODO: this won't work for GUI Code
     /*DepthTest.disable();
     CullFace.disable();*/
+    // Working in pixels makes the glyphs line up with the screen's pixels
+    let window_size = Vec2((window_size.x as f32 * self.scale).round() as i32, (window_size.y as f32 * self.scale).round() as i32);
+    let loc = Vec2((loc.x as f32 * self.scale).round() as i32, (loc.y as f32 * self.scale).round() as i32);
     self.render_program_matrix_uni.set(Mat4::ortho(window_size.x as f32, window_size.y as f32));
     let mut iterator_a = str.chars();
     let mut iterator_b = str.chars().skip(1);
@@ -220,7 +249,10 @@ impl Font_ {
 
 struct Font_ {
   face: freetype::Face,
+  // In logical units
   size: i32,
+  scale: f32,
+  // These are in pixels
   vert_advance: i32,
   descender: i32,
   framebuffer: Framebuffer,
@@ -252,39 +284,126 @@ struct Glyph {
   advance_x: i32,
 }
 
+/// Names a font and its size without needing the font itself, so text can be measured
+/// without an OpenGL context. Widgets hold these, and `Measure` implementations look them up.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct FontKey {
+  pub id: Id,
+  /// In logical units
+  pub size: i32,
+}
+
+impl FontKey {
+  /// Creates a key that's different from every other key
+  pub fn new(size: i32) -> FontKey {
+    FontKey{id: next_id(), size: size}
+  }
+}
+
+/// The files behind a set of font keys. Windows that share a registry can draw with each
+/// other's keys; each one renders its own copy of a font the first time it uses it.
+#[derive(Clone)]
+pub struct FontRegistry {
+  paths: Rc<RefCell<HashMap<FontKey, Path>>>,
+}
+
+impl FontRegistry {
+  pub fn new() -> FontRegistry {
+    FontRegistry{paths: Rc::new(RefCell::new(HashMap::new()))}
+  }
+
+  /// Adds a font whose size is in logical units, and returns its key
+  pub fn register(&self, path: &Path, size: i32) -> FontKey {
+    let key = FontKey::new(size);
+    self.paths.borrow_mut().insert(key, path.clone());
+    key
+  }
+
+  /// Removes a font; windows drop their copies of it the next time they're drawn
+  pub fn unregister(&self, key: FontKey) {
+    self.paths.borrow_mut().remove(&key);
+  }
+
+  /// The file the font with the given key is loaded from
+  pub fn path(&self, key: FontKey) -> Option<Path> {
+    self.paths.borrow().get(&key).map(|path| path.clone())
+  }
+
+  pub fn contains(&self, key: FontKey) -> bool {
+    self.paths.borrow().contains_key(&key)
+  }
+
+  /// Adds the fonts from another registry, keeping their keys
+  pub fn merge(&self, other: &FontRegistry) {
+    // Collected first, since other may be this registry
+    let entries: Vec<(FontKey, Path)> = other.paths.borrow().iter().map(|(&key, path)| (key, path.clone())).collect();
+    self.paths.borrow_mut().extend(entries.into_iter());
+  }
+}
+
 #[derive(Clone)]
 pub struct Font {
+  key: FontKey,
   inner: Rc<RefCell<Font_>>,
 }
 
 // A wrapper that exposes a public interface without &mut self
 impl Font {
-  pub fn new(loader: &FontLoader, path: &Path, size: i32, cache_program: Rc<
+  /// `size` is in logical units, and `scale` is the number of pixels per logical unit
+  pub fn new(loader: &FontLoader, path: &Path, size: i32, scale: f32, cache_program: Rc<
     GLProgram>, render_program: Rc<GLProgram>) -> Font {
-    Font{inner: Rc::new(RefCell::new(Font_::new(loader, path, size, cache_program, render_program)))}
+    Font::with_key(FontKey::new(size), loader, path, scale, cache_program, render_program)
+  }
+
+  /// Loads a font for a key from a `FontRegistry`, at the key's size
+  pub fn with_key(key: FontKey, loader: &FontLoader, path: &Path, scale: f32, cache_program: Rc<GLProgram>,
+    render_program: Rc<GLProgram>) -> Font {
+    Font{key: key, inner: Rc::new(RefCell::new(Font_::new(loader, path, key.size, scale, cache_program, render_program)))}
+  }
+
+  pub fn key(&self) -> FontKey {
+    self.key
+  }
+
+  /// Renders the font at a new scale; this is done automatically for fonts loaded with
+  /// `GUIWindow::load_font` when the window's content scale changes
+  pub fn set_scale(&self, scale: f32) {
+    self.inner.borrow_mut().set_scale(scale);
   }
 
+  // The rest of these are in logical units
+
   pub fn draw_string(&self, str: &str, loc: Vec2<i32>, color: Color<f32>, window_size: Vec2<i32>) {
     self.inner.borrow_mut().draw_string(str, loc, color, window_size);
   }
   pub fn horiz_advance_between(&self, a: char, b: char) -> i32 {
-    self.inner.borrow_mut().horiz_advance_between(a, b)
+    let mut inner = self.inner.borrow_mut();
+    let advance = inner.horiz_advance_between(a, b);
+    inner.logical(advance)
   }
   pub fn horiz_advance_after(&self, a: char) -> i32 {
-    self.inner.borrow_mut().horiz_advance_after(a)
+    let mut inner = self.inner.borrow_mut();
+    let advance = inner.horiz_advance_after(a);
+    inner.logical(advance)
   }
   pub fn string_width(&self, str: &str) -> i32 {
-    self.inner.borrow_mut().string_width(str)
+    let mut inner = self.inner.borrow_mut();
+    let width = inner.string_width(str);
+    inner.logical(width)
   }
   pub fn string_size(&self, str: &str) -> Vec2<i32> {
-    self.inner.borrow_mut().string_size(str)
+    let mut inner = self.inner.borrow_mut();
+    let size = inner.string_size(str);
+    Vec2(inner.logical(size.x), inner.logical(size.y))
   }
   /// The recommended vertical distance between lines of text
   pub fn vert_advance(&self) -> i32 {
-    self.inner.borrow().vert_advance
+    let inner = self.inner.borrow();
+    inner.logical(inner.vert_advance)
   }
   /// The distance from the baseline to the lowest point on the lowest char in the font; probably negative
   pub fn descender(&self) -> i32 {
-    self.inner.borrow().descender
+    let inner = self.inner.borrow();
+    inner.logical(inner.descender)
   }
 }
@@ -3,8 +3,11 @@ extern crate freetype;
 extern crate image;
 
 use std::rc::Rc;
+use std::cell::RefCell;
+use std::mem;
 use std::collections::hash_map::*;
 use std::num::Float;
+use std::num::Int;
 
 use image::DynamicImage;
 use image::GenericImage;
@@ -21,20 +24,21 @@ use new_gl_program::*;
 
 // TODO: background color
 pub struct ButtonWidget {
-  font: Font,
+  font: FontKey,
   text: String,
   text_color: Color<f32>,
   id: Id,
   was_pressed: bool,
+  dirty: bool,
 }
 
 impl ButtonWidget {
-  pub fn new(font: Font, text: &str, text_color: Color<f32>) -> ButtonWidget {
-    ButtonWidget{font: font, text: text.to_string(), text_color: text_color, id: next_id(), was_pressed: false}
+  pub fn new(font: FontKey, text: &str, text_color: Color<f32>) -> ButtonWidget {
+    ButtonWidget{font: font, text: text.to_string(), text_color: text_color, id: next_id(), was_pressed: false, dirty: true}
   }
 
   pub fn text(&self) -> &str {self.text.as_slice()}
-  pub fn set_text(&mut self, text: &str) {self.text = text.to_string();}
+  pub fn set_text(&mut self, text: &str) {self.text = text.to_string(); self.dirty = true;}
 
   pub fn was_pressed(&self) -> bool {self.was_pressed}
 }
@@ -44,19 +48,28 @@ impl Widget for ButtonWidget {
 
   fn draw(&mut self, pos: Vec2<i32>, size: Vec2<i32>, window: &mut GUIWindow) {
     let window_size = window.window_size;
-    self.font.draw_string(self.text.as_slice(), pos, self.text_color, window_size);
+    window.font(self.font).draw_string(self.text.as_slice(), pos, self.text_color, window_size);
 
     // TODO: move this somewhere else
     self.was_pressed = false;
   }
 
-  fn min_size(&self, window: &mut GUIWindow) -> Vec2<i32> {
-    self.font.string_size(self.text.as_slice())
+  fn min_size(&self, measure: &mut Measure) -> Vec2<i32> {
+    measure.string_size(self.font, self.text.as_slice())
+  }
+
+  // Buttons don't stretch to fill the space they're given
+  fn max_size(&self, measure: &mut Measure) -> Vec2<i32> {
+    self.min_size(measure)
+  }
+
+  fn take_dirty(&mut self) -> bool {
+    mem::replace(&mut self.dirty, false)
   }
 
   fn handle_event(&mut self, event: Event, window: &mut GUIWindow) {
     match event {
-      Event::MouseButton(glfw::MouseButtonLeft, Action::Press, _, _) => self.was_pressed = true,
+      Event::Click(glfw::MouseButtonLeft, _, _) => self.was_pressed = true,
       _ => ()
     }
   }
@@ -64,20 +77,82 @@ impl Widget for ButtonWidget {
 
 // TODO: background color
 pub struct LabelWidget {
-  font: Font,
+  font: FontKey,
   text: String,
   text_color: Color<f32>,
   id: Id,
+  wrap: bool,
+  dirty: bool,
+  // The width and content scale the text was last broken into lines at, and the lines
+  lines: RefCell<Option<(i32, f32, Vec<String>)>>,
 }
 
 // TODO: more getters/setters, also for buttons
 impl LabelWidget {
-  pub fn new(font: Font, text: &str, text_color: Color<f32>) -> LabelWidget {
-    LabelWidget{font: font, text: text.to_string(), text_color: text_color, id: next_id()}
+  pub fn new(font: FontKey, text: &str, text_color: Color<f32>) -> LabelWidget {
+    LabelWidget{font: font, text: text.to_string(), text_color: text_color, id: next_id(), wrap: false, dirty: true,
+      lines: RefCell::new(None)}
+  }
+
+  /// Creates a label whose text is broken into multiple lines when it's too narrow
+  pub fn new_wrapped(font: FontKey, text: &str, text_color: Color<f32>) -> LabelWidget {
+    LabelWidget{wrap: true, ..LabelWidget::new(font, text, text_color)}
   }
 
   pub fn text(&self) -> &str {self.text.as_slice()}
-  pub fn set_text(&mut self, text: &str) {self.text = text.to_string();}
+  pub fn set_text(&mut self, text: &str) {self.text = text.to_string(); self.dirty = true; *self.lines.borrow_mut() = None;}
+
+  pub fn wrap(&self) -> bool {self.wrap}
+  pub fn set_wrap(&mut self, wrap: bool) {self.wrap = wrap; self.dirty = true; *self.lines.borrow_mut() = None;}
+
+  // The lines the text is split into by explicit line breaks; there's always at least one
+  fn paragraphs(&self) -> Vec<&str> {
+    let paragraphs: Vec<&str> = self.text.as_slice().lines().collect();
+    if paragraphs.is_empty() {vec![""]} else {paragraphs}
+  }
+
+  // The lines the text is drawn in when it's `width` wide. These are kept until the width,
+  // content scale or text changes, since breaking the text into lines measures every word.
+  fn lines(&self, width: i32, measure: &mut Measure) -> Vec<String> {
+    let scale = measure.content_scale();
+    match *self.lines.borrow() {
+      Some((lines_width, lines_scale, ref lines)) if lines_width == width && lines_scale == scale => return lines.clone(),
+      _ => (),
+    }
+    let lines = self.break_lines(width, measure);
+    *self.lines.borrow_mut() = Some((width, scale, lines.clone()));
+    lines
+  }
+
+  // Breaks the text into lines at most `width` wide; words wider than that get a line
+  // to themselves. Line breaks in the text always start a new line.
+  fn break_lines(&self, width: i32, measure: &mut Measure) -> Vec<String> {
+    if !self.wrap {
+      return self.paragraphs().iter().map(|paragraph| paragraph.to_string()).collect();
+    }
+    let mut lines = Vec::new();
+    for paragraph in self.paragraphs().iter() {
+      let mut line = String::new();
+      for word in paragraph.words() {
+        let candidate = if line.is_empty() {word.to_string()} else {format!("{} {}", line, word)};
+        if line.is_empty() || measure.string_width(self.font, candidate.as_slice()) <= width {
+          line = candidate;
+        } else {
+          lines.push(line);
+          line = word.to_string();
+        }
+      }
+      lines.push(line);
+    }
+    lines
+  }
+
+  // The size of the text when it's only broken at explicit line breaks
+  fn unwrapped_size(&self, measure: &mut Measure) -> Vec2<i32> {
+    let paragraphs = self.paragraphs();
+    let width = paragraphs.iter().map(|paragraph| measure.string_width(self.font, *paragraph)).max().unwrap_or(0);
+    Vec2(width, paragraphs.len() as i32 * measure.vert_advance(self.font))
+  }
 }
 
 impl Widget for LabelWidget {
@@ -85,11 +160,34 @@ impl Widget for LabelWidget {
 
   fn draw(&mut self, pos: Vec2<i32>, size: Vec2<i32>, window: &mut GUIWindow) {
     let window_size = window.window_size;
-    self.font.draw_string(self.text.as_slice(), pos, self.text_color, window_size);
+    let vert_advance = window.vert_advance(self.font);
+    let mut line_pos = pos;
+    for line in self.lines(size.x, window).iter() {
+      window.font(self.font).draw_string(line.as_slice(), line_pos, self.text_color, window_size);
+      line_pos.y += vert_advance;
+    }
   }
 
-  fn min_size(&self, window: &mut GUIWindow) -> Vec2<i32> {
-    self.font.string_size(self.text.as_slice())
+  fn min_size(&self, measure: &mut Measure) -> Vec2<i32> {
+    if self.wrap {
+      // As narrow as the widest word, and one line tall; the real height comes from height_for_width
+      let widest_word = self.text.as_slice().words().map(|word| measure.string_width(self.font, word)).max().unwrap_or(0);
+      Vec2(widest_word, measure.vert_advance(self.font))
+    } else {
+      self.unwrapped_size(measure)
+    }
+  }
+
+  fn preferred_size(&self, measure: &mut Measure) -> Vec2<i32> {
+    self.unwrapped_size(measure)
+  }
+
+  fn height_for_width(&self, width: i32, measure: &mut Measure) -> i32 {
+    self.lines(width, measure).len() as i32 * measure.vert_advance(self.font)
+  }
+
+  fn take_dirty(&mut self) -> bool {
+    mem::replace(&mut self.dirty, false)
   }
 }
 
@@ -108,7 +206,10 @@ impl Widget for EmptyWidget {
 
   fn draw(&mut self, pos: Vec2<i32>, size: Vec2<i32>, window: &mut GUIWindow) {}
 
-  fn min_size(&self, window: &mut GUIWindow) -> Vec2<i32> {self.min_size}
+  fn min_size(&self, measure: &mut Measure) -> Vec2<i32> {self.min_size}
+
+  // The size can't change after the widget is created
+  fn take_dirty(&mut self) -> bool {false}
 }
 
 #[derive(Debug)]
@@ -171,7 +272,7 @@ impl Widget for ImageWidget {
     self.image_fixture.draw(window);
   }
 
-  fn min_size(&self, window: &mut GUIWindow) -> Vec2<i32> {
+  fn min_size(&self, measure: &mut Measure) -> Vec2<i32> {
     match self.resize_mode {
       ImageResizeMode::NativeSize => image_size(self.image_fixture.image()),
       _ => Vec2::zero()
@@ -202,24 +303,42 @@ impl<T: Widget> Widget for BorderWidget<T> {
     // TODO
   }
 
-  fn min_size(&self, window: &mut GUIWindow) -> Vec2<i32> {
-    self.inner.min_size(window) + Vec2(self.border_width, self.border_width)*2
+  fn min_size(&self, measure: &mut Measure) -> Vec2<i32> {
+    self.inner.min_size(measure) + Vec2(self.border_width, self.border_width)*2
+  }
+
+  fn max_size(&self, measure: &mut Measure) -> Vec2<i32> {
+    let max = self.inner.max_size(measure);
+    let border = self.border_width*2;
+    Vec2(max.x.saturating_add(border), max.y.saturating_add(border))
+  }
+
+  fn preferred_size(&self, measure: &mut Measure) -> Vec2<i32> {
+    self.inner.preferred_size(measure) + Vec2(self.border_width, self.border_width)*2
+  }
+
+  fn height_for_width(&self, width: i32, measure: &mut Measure) -> i32 {
+    self.inner.height_for_width(width - self.border_width*2, measure) + self.border_width*2
   }
 
   fn handle_event(&mut self, event: Event, window: &mut GUIWindow) {
     self.inner.handle_event(event, window);
   }
+
+  fn take_dirty(&mut self) -> bool {
+    self.inner.take_dirty()
+  }
 }
 
 
 
 pub struct FocusTestWidget {
   id: Id,
-  font: Font,
+  font: FontKey,
 }
 
 impl FocusTestWidget {
-  pub fn new(font: Font) -> FocusTestWidget {
+  pub fn new(font: FontKey) -> FocusTestWidget {
     FocusTestWidget{font: font, id: next_id()}
   }
 }
@@ -229,21 +348,25 @@ impl Widget for FocusTestWidget {
 
   fn draw(&mut self, pos: Vec2<i32>, size: Vec2<i32>, window: &mut GUIWindow) {
     let window_size = window.window_size;
-    let focused = window.focused == Some(self.id);
+    let focused = window.focused() == Some(self.id);
     if focused {
-      self.font.draw_string("Focused!", pos, Color::red(), window_size);
+      window.font(self.font).draw_string("Focused!", pos, Color::red(), window_size);
     } else {
-      self.font.draw_string("Not focused!", pos, Color::blue(), window_size);
+      window.font(self.font).draw_string("Not focused!", pos, Color::blue(), window_size);
     }
   }
 
-  fn min_size(&self, window: &mut GUIWindow) -> Vec2<i32> {
+  fn min_size(&self, measure: &mut Measure) -> Vec2<i32> {
     Vec2(100, 100)
   }
 
+  fn take_dirty(&mut self) -> bool {false}
+
+  fn focusable(&self) -> bool {true}
+
   fn handle_event(&mut self, event: Event, window: &mut GUIWindow) {
     match event {
-      Event::MouseButton(glfw::MouseButtonLeft, Action::Press, _, _) => window.focused = Some(self.id),
+      Event::MouseButton(glfw::MouseButtonLeft, Action::Press, _, _) => window.focus(Some(self.id)),
       _ => ()
     }
   }